@@ -0,0 +1,163 @@
+use anyhow::Result;
+
+// Pieces are stored high bit first, so piece 0 is the 0x80 bit of the first byte
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Bitfield {
+    bits: Vec<u8>,
+    pieces: usize,
+}
+
+impl Bitfield {
+    pub(crate) fn new(pieces: usize) -> Self {
+        Self {
+            bits: vec![0; pieces.div_ceil(8)],
+            pieces,
+        }
+    }
+
+    pub(crate) fn full(pieces: usize) -> Self {
+        let mut bitfield = Self::new(pieces);
+        for piece in 0..pieces {
+            bitfield.bits[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        bitfield
+    }
+
+    pub(crate) fn from_payload(payload: &[u8], pieces: usize) -> Result<Self> {
+        let expected = pieces.div_ceil(8);
+        anyhow::ensure!(
+            payload.len() == expected,
+            "bitfield is {} bytes long, expected {expected} for {pieces} pieces",
+            payload.len()
+        );
+
+        // Spare bits at the end must be cleared according to the spec
+        let spare = expected * 8 - pieces;
+        if spare > 0 {
+            let mask = (1u8 << spare) - 1;
+            anyhow::ensure!(
+                payload[expected - 1] & mask == 0,
+                "bitfield has spare bits set"
+            );
+        }
+
+        Ok(Self {
+            bits: payload.to_vec(),
+            pieces,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pieces
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub(crate) fn has_any(&self) -> bool {
+        self.bits.iter().any(|&byte| byte != 0)
+    }
+
+    pub(crate) fn has_all(&self) -> bool {
+        (0..self.pieces).all(|piece| self.has(piece))
+    }
+
+    // Pieces both bitfields have
+    pub(crate) fn intersection(&self, other: &Bitfield) -> Bitfield {
+        Self {
+            bits: self
+                .bits
+                .iter()
+                .zip(&other.bits)
+                .map(|(a, b)| a & b)
+                .collect(),
+            pieces: self.pieces.min(other.pieces),
+        }
+    }
+
+    pub(crate) fn has(&self, index: usize) -> bool {
+        index < self.pieces && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    // Whether the bit wasn't already set
+    pub(crate) fn set(&mut self, index: usize) -> Result<bool> {
+        anyhow::ensure!(
+            index < self.pieces,
+            "piece {index} is out of range for {} pieces",
+            self.pieces
+        );
+        let added = !self.has(index);
+        self.bits[index / 8] |= 0x80 >> (index % 8);
+
+        Ok(added)
+    }
+
+    pub(crate) fn unset(&mut self, index: usize) {
+        if index < self.pieces {
+            self.bits[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_payload_length() {
+        for (payload, pieces) in [
+            (&[][..], 1),
+            (&[0x80][..], 9),
+            (&[0x80, 0x00][..], 8),
+            (&[0x00, 0x00][..], 0),
+        ] {
+            assert!(Bitfield::from_payload(payload, pieces).is_err(), "{pieces}");
+        }
+
+        assert_eq!(Bitfield::from_payload(&[], 0).unwrap().len(), 0);
+        let bitfield = Bitfield::from_payload(&[0xff, 0x80], 9).unwrap();
+        assert!(bitfield.has_all());
+        assert_eq!(bitfield, Bitfield::full(9));
+    }
+
+    #[test]
+    fn from_payload_spare_bits() {
+        // 10 pieces leave the low 6 bits of the second byte spare
+        for last in [0x01, 0x20, 0x3f] {
+            assert!(Bitfield::from_payload(&[0xff, 0xc0 | last], 10).is_err());
+        }
+        assert!(Bitfield::from_payload(&[0xff, 0xc0], 10).is_ok());
+
+        // Nothing is spare with whole bytes
+        assert!(Bitfield::from_payload(&[0xff, 0xff], 16).is_ok());
+    }
+
+    #[test]
+    fn set() {
+        let mut bitfield = Bitfield::new(10);
+        assert!(!bitfield.has_any());
+
+        assert!(bitfield.set(0).unwrap());
+        assert!(bitfield.set(9).unwrap());
+        assert!(!bitfield.set(9).unwrap());
+        assert!(!bitfield.set(0).unwrap());
+        assert!(bitfield.set(10).is_err());
+        assert_eq!(bitfield.as_bytes(), [0x80, 0x40]);
+
+        assert!(bitfield.has(0) && bitfield.has(9));
+        assert!(!bitfield.has(1) && !bitfield.has(10));
+        assert!(!bitfield.has_all());
+
+        bitfield.unset(9);
+        bitfield.unset(10);
+        assert!(bitfield.set(9).unwrap());
+    }
+
+    #[test]
+    fn full() {
+        assert_eq!(Bitfield::full(10).as_bytes(), [0xff, 0xc0]);
+        assert_eq!(Bitfield::full(8).as_bytes(), [0xff]);
+        assert!(Bitfield::full(0).has_all());
+    }
+}
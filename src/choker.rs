@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+use crate::swarm::{PeerCommand, PeerHandle, Swarm};
+
+pub(crate) const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// The optimistic unchoke moves on every third round, so every 30 seconds
+const OPTIMISTIC_ROUNDS: u64 = 3;
+// Fresh peers have nothing to trade yet, so they get a better shot at the
+// optimistic slot to get them started
+const NEW_PEER_AGE: Duration = Duration::from_secs(60);
+const NEW_PEER_WEIGHT: usize = 3;
+
+// Tit-for-tat: upload slots go to whoever gives us the most in return, plus
+// one slot rotating between everyone else so new peers get a chance
+pub(crate) struct Choker {
+    slots: usize,
+    round: u64,
+    optimistic: Option<SocketAddrV4>,
+    // Transfer totals as of the last round, for working out rates
+    last: HashMap<SocketAddrV4, (u64, u64)>,
+    last_round: Instant,
+    rng: Rng,
+}
+
+impl Choker {
+    pub(crate) fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            round: 0,
+            optimistic: None,
+            last: HashMap::new(),
+            last_round: Instant::now(),
+            rng: Rng::new(),
+        }
+    }
+
+    pub(crate) async fn run(mut self, swarm: Arc<Swarm>) {
+        let mut interval = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.rechoke(&swarm),
+                _ = swarm.interest_changed() => self.fill_free_slots(&swarm),
+            }
+        }
+    }
+
+    // Newly interested peers shouldn't have to wait for the next round if
+    // there are slots going spare
+    fn fill_free_slots(&self, swarm: &Swarm) {
+        let peers = swarm.peers();
+        let mut free = (self.slots + 1)
+            .saturating_sub(peers.iter().filter(|peer| !peer.stats.is_choked()).count());
+
+        for peer in peers {
+            if free == 0 {
+                break;
+            }
+
+            if peer.stats.is_interested() && peer.stats.is_choked() {
+                peer.send(PeerCommand::Unchoke);
+                free -= 1;
+            }
+        }
+    }
+
+    fn rechoke(&mut self, swarm: &Swarm) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_round).as_secs_f64().max(1.0);
+        self.last_round = now;
+
+        // Once we have everything there is nothing to reciprocate, so reward
+        // the peers we can push data to fastest instead
+        let seeding = swarm.picker().is_finished();
+
+        let peers = swarm.peers();
+        let mut last = HashMap::with_capacity(peers.len());
+        let mut candidates = Vec::new();
+        for peer in &peers {
+            let totals = (peer.stats.downloaded(), peer.stats.uploaded());
+            let (down, up) = self.last.get(&peer.address).copied().unwrap_or((0, 0));
+            last.insert(peer.address, totals);
+
+            if peer.stats.is_interested() {
+                // A peer that reconnected from the same address starts
+                // counting from zero again
+                let rate = if seeding {
+                    totals.1.saturating_sub(up) as f64 / elapsed
+                } else {
+                    totals.0.saturating_sub(down) as f64 / elapsed
+                };
+                candidates.push((peer.address, rate, peer.stats.is_snubbed()));
+            }
+        }
+        self.last = last;
+
+        // Peers snubbing us don't get anything in return, only the optimistic
+        // slot can pick them up
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut unchoked: Vec<SocketAddrV4> = candidates
+            .iter()
+            .filter(|(_, _, snubbed)| seeding || !snubbed)
+            .take(self.slots)
+            .map(|(address, _, _)| *address)
+            .collect();
+
+        let optimistic_gone = self
+            .optimistic
+            .is_none_or(|address| !candidates.iter().any(|(a, _, _)| *a == address));
+        if optimistic_gone || self.round.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            self.optimistic = self.pick_optimistic(&peers, &unchoked);
+        }
+        self.round += 1;
+
+        if let Some(optimistic) = self.optimistic {
+            if !unchoked.contains(&optimistic) {
+                unchoked.push(optimistic);
+            }
+        }
+
+        for peer in peers {
+            let unchoke = unchoked.contains(&peer.address);
+            if unchoke == peer.stats.is_choked() {
+                peer.send(if unchoke {
+                    PeerCommand::Unchoke
+                } else {
+                    PeerCommand::Choke
+                });
+            }
+        }
+    }
+
+    fn pick_optimistic(
+        &mut self,
+        peers: &[PeerHandle],
+        unchoked: &[SocketAddrV4],
+    ) -> Option<SocketAddrV4> {
+        let now = Instant::now();
+        let mut weighted = Vec::new();
+        for peer in peers {
+            if !peer.stats.is_interested() || unchoked.contains(&peer.address) {
+                continue;
+            }
+
+            let weight = if now.duration_since(peer.stats.connected_at) < NEW_PEER_AGE {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            };
+            weighted.extend(std::iter::repeat_n(peer.address, weight));
+        }
+
+        if weighted.is_empty() {
+            return None;
+        }
+
+        Some(weighted[self.rng.below(weighted.len())])
+    }
+}
@@ -0,0 +1,347 @@
+use std::fmt;
+
+// Azureus style ids start with a dash, two letters for the client, four
+// characters of version and another dash, like -qB4450-
+const AZUREUS: &[(&str, &str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("AR", "Arctic"),
+    ("AT", "Artemis"),
+    ("AV", "Avicora"),
+    ("AX", "BitPump"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BE", "Baretorrent"),
+    ("BF", "Bitflu"),
+    ("BG", "BTG"),
+    ("BI", "BiglyBT"),
+    ("BL", "BitBlinder"),
+    ("BP", "BitTorrent Pro"),
+    ("BR", "BitRocket"),
+    ("BS", "BTSlave"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("BX", "BittorrentX"),
+    ("CC", "bittorrent-starter-rust"),
+    ("CD", "Enhanced CTorrent"),
+    ("CT", "CTorrent"),
+    ("DE", "Deluge"),
+    ("DP", "Propagate Data Client"),
+    ("EB", "EBit"),
+    ("ES", "Electric Sheep"),
+    ("FC", "FileCroc"),
+    ("FD", "Free Download Manager"),
+    ("FT", "FoxTorrent"),
+    ("FX", "Freebox BitTorrent"),
+    ("GS", "GSTorrent"),
+    ("HK", "Hekate"),
+    ("HL", "Halite"),
+    ("HM", "hMule"),
+    ("HN", "Hydranode"),
+    ("IL", "iLivid"),
+    ("JS", "Justseed.it"),
+    ("JT", "JavaTorrent"),
+    ("KG", "KGet"),
+    ("KT", "KTorrent"),
+    ("LC", "LeechCraft"),
+    ("LH", "LH-ABC"),
+    ("LP", "Lphant"),
+    ("LT", "libtorrent (rakshasa)"),
+    ("lt", "libtorrent (Rasterbar)"),
+    ("LW", "LimeWire"),
+    ("MK", "Meerkat"),
+    ("MO", "MonoTorrent"),
+    ("MP", "MooPolice"),
+    ("MR", "Miro"),
+    ("MT", "MoonlightTorrent"),
+    ("NB", "Net::BitTorrent"),
+    ("NX", "Net Transport"),
+    ("OS", "OneSwarm"),
+    ("OT", "OmegaTorrent"),
+    ("PB", "Protocol::BitTorrent"),
+    ("PD", "Pando"),
+    ("PI", "PicoTorrent"),
+    ("PT", "PHPTracker"),
+    ("qB", "qBittorrent"),
+    ("QD", "QQDownload"),
+    ("QT", "Qt 4 Torrent example"),
+    ("RT", "Retriever"),
+    ("RZ", "RezTorrent"),
+    ("SB", "Swiftbit"),
+    ("SD", "Thunder"),
+    ("SM", "SoMud"),
+    ("SP", "BitSpirit"),
+    ("SS", "SwarmScope"),
+    ("ST", "SymTorrent"),
+    ("st", "sharktorrent"),
+    ("SZ", "Shareaza"),
+    ("TB", "Torch"),
+    ("TE", "terasaur Seed Bank"),
+    ("TL", "Tribler"),
+    ("TN", "TorrentDotNET"),
+    ("TR", "Transmission"),
+    ("TS", "Torrentstorm"),
+    ("TT", "TuoTu"),
+    ("UL", "uLeecher!"),
+    ("UM", "µTorrent for Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WD", "WebTorrent Desktop"),
+    ("WT", "BitLet"),
+    ("WW", "WebTorrent"),
+    ("WY", "FireTorrent"),
+    ("XF", "Xfplay"),
+    ("XL", "Xunlei"),
+    ("XS", "XSwifter"),
+    ("XT", "XanTorrent"),
+    ("XX", "Xtorrent"),
+    ("ZT", "ZipTorrent"),
+];
+
+// Shadow style ids have a single letter for the client, up to five version
+// characters and dashes, like S58B-----
+const SHADOW: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+const SHADOW_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+// Mainline style ids are a letter followed by dash separated numbers, like
+// M4-3-6--
+const MAINLINE: &[(u8, &str)] = &[(b'M', "BitTorrent"), (b'Q', "Queen Bee")];
+
+// What a peer id says about the software on the other end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Client {
+    pub(crate) name: String,
+    pub(crate) version: Option<String>,
+}
+
+impl Client {
+    pub(crate) fn identify(peer_id: &[u8; 20]) -> Self {
+        azureus(peer_id)
+            .or_else(|| special(peer_id))
+            .or_else(|| mainline(peer_id))
+            .or_else(|| shadow(peer_id))
+            .unwrap_or_else(|| Self::new("unknown", None))
+    }
+
+    fn new(name: &str, version: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            version,
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{} {version}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+fn azureus(id: &[u8; 20]) -> Option<Client> {
+    if id[0] != b'-' || id[7] != b'-' || !id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+
+    let code = std::str::from_utf8(&id[1..3]).ok()?;
+    let version = &id[3..7];
+    let name = AZUREUS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("unknown ({code})"));
+
+    // Transmission went from 2.94 to 3.00 before going semver with 4.0.0
+    let version = match code {
+        "TR" if version[0] < b'4' => format!(
+            "{}.{}{}",
+            version[0] as char, version[1] as char, version[2] as char
+        ),
+        // A letter at the end is a build tag rather than a number
+        _ if version[3].is_ascii_alphabetic() => {
+            dotted(&version[..3], |digit| (digit as char).to_digit(36))
+        }
+        _ => dotted(version, |digit| (digit as char).to_digit(36)),
+    };
+
+    Some(Client {
+        name,
+        version: Some(version),
+    })
+}
+
+fn shadow(id: &[u8; 20]) -> Option<Client> {
+    let (_, name) = SHADOW.iter().find(|(letter, _)| *letter == id[0])?;
+
+    // The version runs up to the dashes, which have to be there
+    let end = id[1..6]
+        .iter()
+        .position(|&c| c == b'-')
+        .map_or(6, |at| at + 1);
+    if end == 1 || id[end..end + 2] != *b"--" {
+        return None;
+    }
+    let version = dotted(&id[1..end], |digit| {
+        SHADOW_DIGITS
+            .iter()
+            .position(|&c| c == digit)
+            .map(|at| at as u32)
+    });
+
+    Some(Client::new(name, Some(version)))
+}
+
+fn mainline(id: &[u8; 20]) -> Option<Client> {
+    let (_, name) = MAINLINE.iter().find(|(letter, _)| *letter == id[0])?;
+
+    // Up to three numbers of one or two digits between the dashes
+    let rest = std::str::from_utf8(&id[1..8]).ok()?;
+    let mut parts = rest.trim_end_matches('-').split('-');
+    let numbers: Vec<&str> = parts.by_ref().take(3).collect();
+    let valid = numbers.len() == 3
+        && parts.next().is_none()
+        && numbers
+            .iter()
+            .all(|n| !n.is_empty() && n.len() <= 2 && n.bytes().all(|c| c.is_ascii_digit()));
+    if !valid {
+        return None;
+    }
+
+    Some(Client::new(name, Some(numbers.join("."))))
+}
+
+// Ids that follow none of the usual schemes
+fn special(id: &[u8; 20]) -> Option<Client> {
+    let starts = |prefix: &[u8]| id.starts_with(prefix);
+    let digits = |range: std::ops::Range<usize>| {
+        std::str::from_utf8(&id[range])
+            .ok()
+            .filter(|digits| digits.bytes().all(|c| c.is_ascii_digit()))
+            .map(str::to_string)
+    };
+
+    if starts(b"exbc") || starts(b"FUTB") || starts(b"xUTB") {
+        // Two raw version bytes, BitLord marks itself after them
+        let name = if &id[6..10] == b"LORD" {
+            "BitLord"
+        } else {
+            "BitComet"
+        };
+        return Some(Client::new(name, Some(format!("{}.{:02}", id[4], id[5]))));
+    }
+    if starts(b"-ML") {
+        let version = String::from_utf8_lossy(&id[3..8]).into_owned();
+        return Some(Client::new("MLDonkey", Some(version)));
+    }
+    if starts(b"XBT") {
+        let version = digits(3..6)?;
+        return Some(Client::new("XBT Client", Some(dotted_str(&version))));
+    }
+    if starts(b"OP") {
+        let version = digits(2..6)?;
+        return Some(Client::new("Opera", Some(version)));
+    }
+    if starts(b"AZ2500BT") {
+        return Some(Client::new("BitTyrant", None));
+    }
+    if starts(b"Plus") {
+        let version = digits(4..7)?;
+        return Some(Client::new("Plus!", Some(dotted_str(&version))));
+    }
+    if starts(b"btfans") {
+        return Some(Client::new("SimpleBT", None));
+    }
+    if starts(b"Deadman Walking-") {
+        return Some(Client::new("Deadman", None));
+    }
+    if starts(b"Azureus") {
+        return Some(Client::new("Vuze", Some("2.0.3.2".to_string())));
+    }
+
+    None
+}
+
+// Every character is a number of its own. Trailing zeros are dropped as long
+// as there's a major and minor left.
+fn dotted(version: &[u8], value: impl Fn(u8) -> Option<u32>) -> String {
+    let mut numbers: Vec<u32> = version.iter().map_while(|&c| value(c)).collect();
+    while numbers.len() > 2 && numbers.last() == Some(&0) {
+        numbers.pop();
+    }
+
+    numbers
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn dotted_str(version: &str) -> String {
+    dotted(version.as_bytes(), |digit| (digit as char).to_digit(10))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `prefix` padded out to a full peer id
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut id = [b'x'; 20];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    #[test]
+    fn identify() {
+        let cases: &[([u8; 20], &str)] = &[
+            // Azureus
+            (id(b"-qB4450-"), "qBittorrent 4.4.5"),
+            (id(b"-lt0D60-"), "libtorrent (Rasterbar) 0.13.6"),
+            (id(b"-TR2940-"), "Transmission 2.94"),
+            (id(b"-TR4060-"), "Transmission 4.0.6"),
+            (id(b"-UT355W-"), "µTorrent 3.5.5"),
+            (id(b"-ZZ1234-"), "unknown (ZZ) 1.2.3.4"),
+            // Shadow
+            (id(b"S58B-----"), "Shadow's client 5.8.11"),
+            (id(b"T03I-----"), "BitTornado 0.3.18"),
+            (id(b"Q4B---"), "BTQueue 4.11"),
+            // Mainline
+            (id(b"M4-3-6--"), "BitTorrent 4.3.6"),
+            (id(b"M7-10-2-"), "BitTorrent 7.10.2"),
+            (id(b"Q1-2-3--"), "Queen Bee 1.2.3"),
+            // Special cases
+            (id(b"exbc\x00\x38LORD"), "BitLord 0.56"),
+            (id(b"exbc\x00\x38abcd"), "BitComet 0.56"),
+            (id(b"XBT054d-"), "XBT Client 0.5.4"),
+            (id(b"AZ2500BT"), "BitTyrant"),
+            // Nothing we know
+            ([0xff; 20], "unknown"),
+            (id("-é1234-".as_bytes()), "unknown"),
+            (id(b"-qB4450X"), "unknown"),
+            (id(b"M4-3-6-7-"), "unknown"),
+            (id(b"S-------"), "unknown"),
+            ([0; 20], "unknown"),
+        ];
+
+        for (id, expected) in cases {
+            assert_eq!(
+                Client::identify(id).to_string(),
+                *expected,
+                "{}",
+                id.escape_ascii()
+            );
+        }
+    }
+}
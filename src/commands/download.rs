@@ -1,95 +1,255 @@
 use anyhow::{Context, Result};
-use sha1::{Digest, Sha1};
-use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::{
+    bitfield::Bitfield,
+    choker::{Choker, DEFAULT_UPLOAD_SLOTS},
+    connector::Connector,
+    listener::Listener,
+    mse::Encryption,
     peer::Peer,
-    torrent::{Torrent, TorrentClass},
-    tracker::TrackerClient,
+    picker::PiecePicker,
+    proxy::{self, ProxyKind},
+    ratelimit::{RateLimits, Throttle},
+    storage::Storage,
+    swarm::Swarm,
+    torrent::Torrent,
+    tracker::{TrackerClient, PORT},
+    transport::{Connection, Dialer},
+    utp::UtpSocket,
 };
 
-pub(crate) async fn piece(output: PathBuf, torrent: PathBuf, piece_id: usize) -> Result<()> {
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+// How long a peer gets to send something towards a single piece
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) async fn piece(
+    output: PathBuf,
+    torrent: PathBuf,
+    piece_id: usize,
+    max_requests: usize,
+    encryption: Encryption,
+    utp: bool,
+    limits: RateLimits,
+) -> Result<()> {
+    let throttle = Throttle::new(limits.global()?, limits.torrent()?);
     let torrent = Torrent::from_file(torrent)?;
     let info_hash = torrent.info_hash()?;
     let peer_response = TrackerClient::peers(&torrent).await?;
 
-    let piece_length = calculate_piece_length(piece_id, &torrent);
+    // Nobody is let in for a single piece, so any port does
+    let utp = match utp_usable(utp) {
+        true if proxy::get().is_some() => Some(UtpSocket::associate().await?),
+        true => Some(UtpSocket::bind(0).await?),
+        false => None,
+    };
+    let dialer = Dialer::new(encryption, utp);
 
-    // This is a stupid hack because for some reason, some of the peers
-    // have an issue with the handshake when it works fine other times
-    let mut peers = vec![];
-    for peer in peer_response.peers.0.into_iter() {
-        if let Ok(peer) = Peer::new(peer, &info_hash).await {
-            peers.push(peer);
-        }
-    }
+    let piece_count = torrent.info.pieces.0.len();
+    let ours = Bitfield::new(piece_count);
+    let mut connector = Connector::new(info_hash, ours.clone(), dialer, throttle.clone());
+    connector.add(peer_response.peers.0);
 
-    anyhow::ensure!(peers.len() > 0, "no available peers");
-    let mut peer = peers.remove(0);
-    drop(peers);
-    let downloaded_piece = peer
-        .download_piece(piece_id, piece_length)
-        .await
-        .context("calling peer to download piece")?;
+    let storage = Storage::for_piece(&torrent, piece_id, &output)
+        .with_context(|| format!("creating {}", output.display()))?;
+    let (tx, mut completed) = mpsc::unbounded_channel();
+    let swarm = Swarm::new(
+        &torrent,
+        PiecePicker::for_piece(&torrent, piece_id)?,
+        storage,
+        ours,
+        throttle,
+        tx,
+    );
 
-    let mut hasher = Sha1::new();
-    hasher.update(&downloaded_piece);
-    let result: [u8; 20] = hasher.finalize().try_into().expect("this should work");
-    anyhow::ensure!(result == torrent.info.pieces.0[piece_id]);
+    // Peers with the piece get a go one after the other, in the order they
+    // come up, until one of them delivers
+    let (delivered_by, client) = loop {
+        let mut peer = loop {
+            anyhow::ensure!(
+                !connector.is_exhausted(),
+                "no available peers could send piece {piece_id}"
+            );
+            // Anything already on its way up when its address got banned
+            // still comes through
+            if let (address, Ok(peer)) = connector.next().await {
+                if peer.has_piece(piece_id) && !swarm.is_banned(address.ip()) {
+                    break peer;
+                }
+            }
+        };
+        peer.set_max_requests(max_requests);
 
-    let mut file = tokio::fs::File::create(&output).await?;
-    file.write_all(&downloaded_piece)
-        .await
-        .with_context(|| format!("writing out piece to {}", output.display()))?;
+        let address = peer.address();
+        match download_from(&mut peer, &swarm, &mut completed).await {
+            Ok(()) => break (address, peer.client().clone()),
+            Err(e) => {
+                eprintln!("moving on from peer {address}: {e:#}");
+                if swarm.is_banned(address.ip()) {
+                    connector.forget(address.ip());
+                }
+            }
+        }
+    };
 
     println!("Piece {} downloaded to {}", piece_id, output.display());
+    println!("Delivered by {delivered_by} ({client})");
 
     Ok(())
 }
 
-// TODO: Multitask this
-pub(crate) async fn full(output: PathBuf, torrent_file: PathBuf) -> Result<()> {
+// Gives up on the peer if it goes a while without sending anything, which
+// also covers it choking us for good
+async fn download_from(
+    peer: &mut Peer<Connection>,
+    swarm: &Swarm,
+    completed: &mut mpsc::UnboundedReceiver<usize>,
+) -> Result<()> {
+    let address = peer.address();
+    let stats = peer.stats();
+    let mut progress = (stats.downloaded(), Instant::now());
+    let mut check = tokio::time::interval(Duration::from_secs(1));
+    let mut stalled = false;
+
+    let run = peer.run(swarm);
+    tokio::pin!(run);
+    loop {
+        tokio::select! {
+            biased;
+
+            Some(_) = completed.recv() => return Ok(()),
+            result = &mut run => {
+                anyhow::ensure!(
+                    !stalled,
+                    "peer sent nothing for {} seconds",
+                    STALL_TIMEOUT.as_secs()
+                );
+                result?;
+                anyhow::bail!("peer went away before sending the piece");
+            }
+            _ = check.tick() => {
+                let downloaded = stats.downloaded();
+                if downloaded != progress.0 {
+                    progress = (downloaded, Instant::now());
+                } else if progress.1.elapsed() >= STALL_TIMEOUT {
+                    // Lets the peer clean up after itself on the way out
+                    stalled = true;
+                    swarm.disconnect(&address);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) async fn full(
+    output: PathBuf,
+    torrent_file: PathBuf,
+    max_requests: usize,
+    encryption: Encryption,
+    utp: bool,
+    limits: RateLimits,
+    stats: bool,
+) -> Result<()> {
+    let throttle = Throttle::new(limits.global()?, limits.torrent()?);
     let torrent = Torrent::from_file(&torrent_file)?;
     let info_hash = torrent.info_hash()?;
     let peer_response = TrackerClient::peers(&torrent)
         .await
         .context("fetching peer list")?;
 
-    let mut peers = Vec::new();
-    for peer in peer_response.peers.0.into_iter() {
-        if let Ok(peer) = Peer::new(peer, &info_hash).await {
-            peers.push(peer);
+    // We told the tracker we'd be on this port, so let peers in through it.
+    // Outgoing uTP goes out the same port, so peers know who is calling.
+    let listener = match Listener::bind(PORT, encryption).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            eprintln!("not accepting incoming peers: {e:#}");
+            None
         }
-    }
+    };
+    // Behind a proxy the listener's port is only good for incoming peers
+    let utp = match utp_usable(utp) {
+        true if proxy::get().is_some() => Some(UtpSocket::associate().await?),
+        true => match &listener {
+            Some(listener) => Some(listener.utp()),
+            None => Some(UtpSocket::bind(0).await?),
+        },
+        false => None,
+    };
+    let dialer = Dialer::new(encryption, utp);
 
-    anyhow::ensure!(peers.len() > 0, "should have at least one peer");
-    let mut peer = peers.remove(0);
-    let mut full_content = Vec::new();
-    //let mut peer = Peer::new(peer_response.peers.0[0], &info_hash).await?;
-    for (id, piece) in torrent.info.pieces.0.iter().enumerate() {
-        let piece_length = calculate_piece_length(id, &torrent);
-        let content = peer
-            .download_piece(id, piece_length)
-            .await
-            .with_context(|| format!("downloading piece {id}"))?;
-
-        let mut hasher = Sha1::new();
-        hasher.update(&content);
-        let result: [u8; 20] = hasher.finalize().try_into().expect("this should work");
-        anyhow::ensure!(result == *piece);
-        full_content.extend(content);
+    let piece_count = torrent.info.pieces.0.len();
+    let ours = Bitfield::new(piece_count);
+    let storage = Storage::open(&torrent, &output)
+        .with_context(|| format!("creating {}", output.display()))?;
+    let (tx, mut completed) = mpsc::unbounded_channel();
+    let swarm = Arc::new(Swarm::new(
+        &torrent,
+        PiecePicker::new(&torrent)?,
+        storage,
+        ours.clone(),
+        throttle,
+        tx,
+    ));
+
+    // Up and running before connecting anywhere, the tracker may well hand
+    // out our own address and that connection needs answering to be spotted
+    if let Some(mut listener) = listener {
+        listener.add(info_hash, swarm.clone());
+        tokio::spawn(listener.run());
     }
 
-    let mut file = tokio::fs::File::create(&output)
-        .await
-        .context("creating output file")?;
+    let mut connector = Connector::new(info_hash, ours, dialer, swarm.throttle());
+    connector.add(peer_response.peers.0);
 
-    file.write_all(&full_content)
-        .await
-        .context("writing file content")?;
+    tokio::spawn(Choker::new(DEFAULT_UPLOAD_SLOTS).run(swarm.clone()));
+
+    let mut tasks = JoinSet::new();
+    let mut report = tokio::time::interval(STATS_INTERVAL);
+    let mut remaining = piece_count;
+    while remaining > 0 {
+        tokio::select! {
+            // Drain finished pieces first so a peer exiting after sending the
+            // last one isn't mistaken for running out of peers
+            biased;
+
+            Some(_) = completed.recv() => {
+                remaining -= 1;
+                connector.set_bitfield(swarm.bitfield());
+            }
+            _ = report.tick(), if stats => print_stats(&swarm),
+            Some(joined) = tasks.join_next() => {
+                if let Ok(Err(e)) = joined {
+                    eprintln!("peer dropped out: {e:#}");
+                }
+            }
+            (address, connected) = connector.next() => {
+                // Failures get retried, nothing to say about them yet
+                if let Ok(mut peer) = connected {
+                    if swarm.is_banned(address.ip()) {
+                        continue;
+                    }
+
+                    peer.set_max_requests(max_requests);
+                    let swarm = swarm.clone();
+                    tasks.spawn(async move { peer.run(&swarm).await });
+                }
+            }
+        }
+
+        anyhow::ensure!(
+            remaining == 0 || !tasks.is_empty() || !connector.is_exhausted(),
+            "ran out of peers with {remaining} pieces left"
+        );
+    }
 
+    if stats {
+        print_stats(&swarm);
+    }
     println!(
         "Downloaded {} to {}",
         torrent_file.display(),
@@ -99,22 +259,58 @@ pub(crate) async fn full(output: PathBuf, torrent_file: PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn calculate_piece_length(piece_id: usize, torrent: &Torrent) -> usize {
-    let length = match torrent.info.t_class {
-        TorrentClass::SingleFile { length } => length,
-        _ => unimplemented!("someday"),
-    };
+// One line per connected peer, busiest first. Rates are payload only, the
+// overhead columns are everything else that went over the wire.
+fn print_stats(swarm: &Swarm) {
+    let mut peers = swarm.peers();
+    peers.sort_by(|a, b| b.stats.download_rate().total_cmp(&a.stats.download_rate()));
 
-    let piece_length = if piece_id == &torrent.info.pieces.0.len() - 1 {
-        let remainder = length % torrent.info.piece_length;
-        if remainder == 0 {
-            torrent.info.piece_length
-        } else {
-            remainder
-        }
-    } else {
-        torrent.info.piece_length
-    };
+    eprintln!(
+        "{:<21} {:<24} {:>6} {:>10} {:>10} {:>9} {:>10} {:>10} {:>9} {:>8} {:>7} {:>6} {:>5}",
+        "peer",
+        "client",
+        "age",
+        "down KiB/s",
+        "down KiB",
+        "ovh bytes",
+        "up KiB/s",
+        "up KiB",
+        "ovh bytes",
+        "latency",
+        "blocks",
+        "reject",
+        "hash"
+    );
+    for peer in peers {
+        let stats = &peer.stats;
+        let latency = match stats.latency() {
+            Some(latency) => format!("{}ms", latency.as_millis()),
+            None => "-".to_string(),
+        };
+        eprintln!(
+            "{:<21} {:<24} {:>5}s {:>10.1} {:>10} {:>9} {:>10.1} {:>10} {:>9} {:>8} {:>7} {:>6} {:>5}",
+            peer.address.to_string(),
+            peer.client.to_string(),
+            stats.age().as_secs(),
+            stats.download_rate() / 1024.0,
+            stats.downloaded() / 1024,
+            stats.overhead_received(),
+            stats.upload_rate() / 1024.0,
+            stats.uploaded() / 1024,
+            stats.overhead_sent(),
+            latency,
+            stats.blocks_received(),
+            stats.blocks_rejected(),
+            stats.hash_failures()
+        );
+    }
+}
 
-    piece_length
+// HTTP proxies only tunnel TCP, so uTP is off behind one rather than failing
+fn utp_usable(utp: bool) -> bool {
+    if utp && proxy::get().is_some_and(|proxy| proxy.kind != ProxyKind::Socks5) {
+        eprintln!("not using uTP, HTTP proxies only carry TCP");
+        return false;
+    }
+    utp
 }
@@ -1,9 +1,10 @@
 use anyhow::{Context, Result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
 
-use crate::peer::Handshake;
+use crate::client::Client;
+use crate::mse::Encryption;
+use crate::peer::{establish_connection, Handshake};
 use crate::torrent::Torrent;
+use crate::transport::Dialer;
 
 use std::net::SocketAddrV4;
 use std::path::Path;
@@ -17,22 +18,13 @@ pub(crate) async fn invoke(file: impl AsRef<Path>, peer: String) -> Result<()> {
         .parse::<SocketAddrV4>()
         .context("parsing peer address")?;
 
-    let mut peer = TcpStream::connect(peer_addr).await?;
-    let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-
-    {
-        let handshake_bytes = handshake.as_bytes_mut();
-        println!("Sending {} bytes for a handshake", handshake_bytes.len());
-
-        peer.write_all(handshake_bytes)
-            .await
-            .context("sending handshake")?;
-        peer.read_exact(handshake_bytes)
-            .await
-            .context("receiving handshake")?;
-    }
+    println!("Sending {} bytes for a handshake", Handshake::LENGTH);
+    let dialer = Dialer::new(Encryption::Disable, None);
+    let (_, handshake) = establish_connection(peer_addr, &info_hash, &dialer).await?;
 
     println!("Peer ID: {}", hex::encode(handshake.peer_id));
+    println!("Client: {}", Client::identify(&handshake.peer_id));
+    println!("Capabilities: {}", handshake.capabilities());
 
     Ok(())
 }
@@ -13,7 +13,7 @@ pub(crate) fn invoke(file: impl AsRef<Path>) -> Result<()> {
     }
 
     println!("Tracker URL: {}", torrent.announce);
-    println!("Info Hash: {}", hex::encode(&info_hash));
+    println!("Info Hash: {}", hex::encode(info_hash));
     println!("Piece Length: {}", info.piece_length);
     println!("Piece Hashes:");
     for piece in info.pieces.0 {
@@ -3,3 +3,4 @@ pub(crate) mod download;
 pub(crate) mod handshake;
 pub(crate) mod info;
 pub(crate) mod peers;
+pub(crate) mod seed;
@@ -1,18 +1,61 @@
 use anyhow::{Context, Result};
+use tokio::task::JoinSet;
+use tokio::time;
 
 use std::path::Path;
+use std::time::Duration;
 
+use crate::client::Client;
+use crate::mse::Encryption;
+use crate::peer::establish_connection;
 use crate::torrent::Torrent;
 use crate::tracker::TrackerClient;
+use crate::transport::Dialer;
 
-pub async fn invoke(file: impl AsRef<Path>) -> Result<()> {
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn invoke(file: impl AsRef<Path>, clients: bool) -> Result<()> {
     let torrent = Torrent::from_file(file).context("loading torrent file")?;
     let response = TrackerClient::peers(&torrent)
         .await
         .context("calling tracker endpoint")?;
 
-    for peer in response.peers.0.iter() {
-        println!("{peer}");
+    if !clients {
+        for peer in response.peers.0.iter() {
+            println!("{peer}");
+        }
+        return Ok(());
+    }
+
+    // The tracker only gives out addresses, the peer ids come from
+    // handshaking with everyone at once
+    let info_hash = torrent.info_hash()?;
+    let dialer = Dialer::new(Encryption::Disable, None);
+    let mut handshakes = JoinSet::new();
+    for (index, &peer) in response.peers.0.iter().enumerate() {
+        let dialer = dialer.clone();
+        handshakes.spawn(async move {
+            let handshake = time::timeout(
+                HANDSHAKE_TIMEOUT,
+                establish_connection(peer, &info_hash, &dialer),
+            )
+            .await;
+            let client = match handshake {
+                Ok(Ok((_, handshake))) => Client::identify(&handshake.peer_id).to_string(),
+                Ok(Err(e)) => format!("unreachable ({e:#})"),
+                Err(_) => "unreachable (timed out)".to_string(),
+            };
+            (index, client)
+        });
+    }
+
+    let mut found = vec![String::new(); response.peers.0.len()];
+    while let Some(joined) = handshakes.join_next().await {
+        let (index, client) = joined.context("handshake task panicked")?;
+        found[index] = client;
+    }
+    for (peer, client) in response.peers.0.iter().zip(found) {
+        println!("{peer} {client}");
     }
 
     Ok(())
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use tokio::sync::mpsc;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{
+    choker::Choker,
+    listener::Listener,
+    mse::Encryption,
+    picker::PiecePicker,
+    ratelimit::{RateLimits, Throttle},
+    storage::Storage,
+    swarm::Swarm,
+    torrent::Torrent,
+    tracker::{Event, TrackerClient},
+};
+
+// How long to wait between announces if the tracker doesn't tell us
+const DEFAULT_INTERVAL: u64 = 1800;
+// Failed announces are retried after this, doubling up to the maximum
+const RETRY_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(1800);
+
+pub(crate) async fn invoke(
+    torrent_file: PathBuf,
+    data: PathBuf,
+    port: u16,
+    upload_slots: usize,
+    encryption: Encryption,
+    limits: RateLimits,
+    super_seed: bool,
+) -> Result<()> {
+    let throttle = Throttle::new(limits.global()?, limits.torrent()?);
+    let torrent = Torrent::from_file(&torrent_file)?;
+    let info_hash = torrent.info_hash()?;
+
+    let storage = Storage::existing(&torrent, &data)
+        .with_context(|| format!("opening {}", data.display()))?;
+    let have = storage
+        .verify(&torrent)
+        .with_context(|| format!("verifying {}", data.display()))?;
+
+    let piece_count = torrent.info.pieces.0.len();
+    let verified = (0..piece_count).filter(|&piece| have.has(piece)).count();
+    anyhow::ensure!(
+        verified > 0,
+        "none of {} matches {}",
+        data.display(),
+        torrent_file.display()
+    );
+    println!(
+        "Verified {verified}/{piece_count} pieces of {}",
+        data.display()
+    );
+    anyhow::ensure!(
+        !super_seed || verified == piece_count,
+        "super-seeding needs all of the data"
+    );
+
+    let left = (0..piece_count)
+        .filter(|&piece| !have.has(piece))
+        .map(|piece| torrent.piece_length(piece))
+        .sum();
+
+    // Nothing gets downloaded, so nothing ever completes either
+    let (tx, _completed) = mpsc::unbounded_channel();
+    let swarm = Arc::new(Swarm::new(
+        &torrent,
+        PiecePicker::seeding(&torrent),
+        storage,
+        have,
+        throttle,
+        tx,
+    ));
+
+    if super_seed {
+        swarm.enable_super_seeding();
+    }
+
+    let mut listener = Listener::bind(port, encryption).await?;
+    listener.add(info_hash, swarm.clone());
+    tokio::spawn(Choker::new(upload_slots).run(swarm));
+
+    match super_seed {
+        true => println!("Super-seeding {} on port {port}", torrent.info.name),
+        false => println!("Seeding {} on port {port}", torrent.info.name),
+    }
+    tokio::select! {
+        result = listener.run() => result.context("accepting peers")?,
+        result = announce(&torrent, port, left) => result.context("announcing to tracker")?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    TrackerClient::announce(&torrent, port, left, Some(Event::Stopped))
+        .await
+        .context("telling the tracker we stopped")?;
+
+    Ok(())
+}
+
+// Only the first announce failing is fatal, later ones just get retried
+async fn announce(torrent: &Torrent, port: u16, left: usize) -> Result<()> {
+    let response = TrackerClient::announce(torrent, port, left, Some(Event::Started)).await?;
+    let mut wait = Duration::from_secs(response.interval.unwrap_or(DEFAULT_INTERVAL));
+    let mut backoff = RETRY_BACKOFF;
+    loop {
+        tokio::time::sleep(wait).await;
+        match TrackerClient::announce(torrent, port, left, None).await {
+            Ok(response) => {
+                wait = Duration::from_secs(response.interval.unwrap_or(DEFAULT_INTERVAL));
+                backoff = RETRY_BACKOFF;
+            }
+            Err(e) => {
+                eprintln!(
+                    "announce failed, trying again in {}s: {e:#}",
+                    backoff.as_secs()
+                );
+                wait = backoff;
+                backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+            }
+        }
+    }
+}
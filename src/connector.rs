@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use crate::bitfield::Bitfield;
+use crate::ipfilter;
+use crate::peer::Peer;
+use crate::ratelimit::Throttle;
+use crate::transport::{Connection, Dialer};
+
+// Connections still on their way up at any one time
+const MAX_HALF_OPEN: usize = 8;
+// For the connect, the handshakes and the first message. Long enough to
+// cover uTP falling back to TCP and MSE falling back to plaintext.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+// Failed peers get another go after this, doubling every time
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_ATTEMPTS: u32 = 4;
+
+struct Candidate {
+    address: SocketAddrV4,
+    attempts: u32,
+    retry_at: Instant,
+}
+
+// Brings up connections to a pool of candidate peers concurrently, so one
+// unreachable address doesn't hold up the rest
+pub(crate) struct Connector {
+    info_hash: [u8; 20],
+    ours: Bitfield,
+    dialer: Dialer,
+    throttle: Throttle,
+    candidates: Vec<Candidate>,
+    pending: JoinSet<(Candidate, Result<Peer<Connection>>)>,
+}
+
+impl Connector {
+    pub(crate) fn new(
+        info_hash: [u8; 20],
+        ours: Bitfield,
+        dialer: Dialer,
+        throttle: Throttle,
+    ) -> Self {
+        Self {
+            info_hash,
+            ours,
+            dialer,
+            throttle,
+            candidates: Vec::new(),
+            pending: JoinSet::new(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, addresses: impl IntoIterator<Item = SocketAddrV4>) {
+        let now = Instant::now();
+        self.candidates.extend(
+            addresses
+                .into_iter()
+                .filter(|address| !ipfilter::is_blocked(address.ip()))
+                .map(|address| Candidate {
+                    address,
+                    attempts: 0,
+                    retry_at: now,
+                }),
+        );
+    }
+
+    // Drops whatever is still queued up for the address, on any port
+    pub(crate) fn forget(&mut self, ip: &Ipv4Addr) {
+        self.candidates
+            .retain(|candidate| candidate.address.ip() != ip);
+    }
+
+    // What new peers get told we have
+    pub(crate) fn set_bitfield(&mut self, ours: Bitfield) {
+        self.ours = ours;
+    }
+
+    // Nothing being tried and nothing left to try
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.candidates.is_empty() && self.pending.is_empty()
+    }
+
+    // The next connection attempt to finish, successful or not. Failed ones
+    // are queued up to be tried again later. Waits forever once exhausted.
+    pub(crate) async fn next(&mut self) -> (SocketAddrV4, Result<Peer<Connection>>) {
+        loop {
+            self.start_due();
+            if self.is_exhausted() {
+                std::future::pending::<()>().await;
+            }
+
+            let retry_at = self
+                .candidates
+                .iter()
+                .map(|candidate| candidate.retry_at)
+                .min();
+            let waiting_for_slot = self.pending.len() >= MAX_HALF_OPEN;
+
+            tokio::select! {
+                Some(joined) = self.pending.join_next() => {
+                    let (mut candidate, result) = joined.expect("connection attempts don't panic");
+                    let address = candidate.address;
+                    if result.is_err() && candidate.attempts < MAX_ATTEMPTS {
+                        candidate.retry_at =
+                            Instant::now() + RETRY_BACKOFF * 2u32.pow(candidate.attempts - 1);
+                        self.candidates.push(candidate);
+                    }
+                    return (address, result);
+                }
+                _ = time::sleep_until(retry_at.unwrap_or_else(Instant::now)),
+                    if retry_at.is_some() && !waiting_for_slot => {}
+            }
+        }
+    }
+
+    fn start_due(&mut self) {
+        let now = Instant::now();
+        while self.pending.len() < MAX_HALF_OPEN {
+            let Some(due) = self
+                .candidates
+                .iter()
+                .position(|candidate| candidate.retry_at <= now)
+            else {
+                break;
+            };
+
+            let mut candidate = self.candidates.swap_remove(due);
+            candidate.attempts += 1;
+
+            let info_hash = self.info_hash;
+            let ours = self.ours.clone();
+            let dialer = self.dialer.clone();
+            let throttle = self.throttle.clone();
+            self.pending.spawn(async move {
+                let address = candidate.address;
+                let result = time::timeout(
+                    CONNECT_TIMEOUT,
+                    Peer::new(address, &info_hash, &ours, &dialer, throttle),
+                )
+                .await
+                .context("timed out connecting to peer")
+                .and_then(|result| result);
+                (candidate, result)
+            });
+        }
+    }
+}
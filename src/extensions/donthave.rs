@@ -0,0 +1,26 @@
+use anyhow::{Context, Result};
+
+use super::{Extension, ExtensionContext};
+
+// BEP 54, peers telling us they no longer have a piece they announced before
+pub(crate) struct DontHave;
+
+impl Extension for DontHave {
+    fn name(&self) -> &'static str {
+        "lt_donthave"
+    }
+
+    fn message(&mut self, context: &mut ExtensionContext, payload: &[u8]) -> Result<()> {
+        let index: [u8; 4] = payload
+            .try_into()
+            .context("donthave should carry a 4 byte piece index")?;
+        let piece = u32::from_be_bytes(index) as usize;
+
+        if context.bitfield.has(piece) {
+            context.bitfield.unset(piece);
+            context.swarm.picker().peer_lost(piece);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use std::collections::BTreeMap;
+
+use crate::bitfield::Bitfield;
+use crate::peer::Message;
+use crate::swarm::Swarm;
+
+mod donthave;
+
+// Extended messages say which extension they are for, zero is the extended
+// handshake itself
+const HANDSHAKE_ID: u8 = 0;
+
+// Everything is optional apart from `m`, and even that can be missing
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ExtendedHandshake {
+    // Extension names mapped to the message ids the sender wants to receive
+    // them as, an id of zero means the extension is turned off
+    #[serde(default)]
+    pub(crate) m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) v: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) p: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reqq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) yourip: Option<ByteBuf>,
+}
+
+// What an extension gets to work with when one of its messages comes in
+pub(crate) struct ExtensionContext<'a> {
+    pub(crate) swarm: &'a Swarm,
+    pub(crate) bitfield: &'a mut Bitfield,
+    // Payloads to send back to the same extension on the other end
+    pub(crate) replies: Vec<Vec<u8>>,
+}
+
+pub(crate) trait Extension: Send {
+    // Key in `m` both ends know the extension by
+    fn name(&self) -> &'static str;
+
+    fn message(&mut self, context: &mut ExtensionContext, payload: &[u8]) -> Result<()>;
+}
+
+// The extensions running on a single connection. Ours are numbered by their
+// position, starting from one, the peer's come from its extended handshake.
+pub(crate) struct Extensions {
+    local: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl Extensions {
+    pub(crate) fn new() -> Self {
+        Self {
+            local: vec![Box::new(donthave::DontHave)],
+            remote: None,
+        }
+    }
+
+    pub(crate) fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    // Fills in `m` and encodes the handshake as an extended message
+    pub(crate) fn handshake(&self, mut handshake: ExtendedHandshake) -> Result<Message> {
+        for (index, extension) in self.local.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_string(), index as i64 + 1);
+        }
+
+        let payload = serde_bencode::to_bytes(&handshake).context("encoding extended handshake")?;
+
+        Ok(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: payload.into(),
+        })
+    }
+
+    // Picks up the peer's handshake, which it is allowed to send again later
+    // to change its mind
+    pub(crate) fn remote_handshake(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        if id != HANDSHAKE_ID {
+            return Ok(());
+        }
+
+        let handshake: ExtendedHandshake = serde_bencode::from_bytes(payload)
+            .context("peer sent an invalid extended handshake")?;
+        self.remote = Some(handshake);
+
+        Ok(())
+    }
+
+    // Hands the message to whichever of our extensions it is for, returning
+    // the messages to send back
+    pub(crate) fn message(
+        &mut self,
+        swarm: &Swarm,
+        bitfield: &mut Bitfield,
+        id: u8,
+        payload: &[u8],
+    ) -> Result<Vec<Message>> {
+        if id == HANDSHAKE_ID {
+            return Ok(Vec::new());
+        }
+
+        // Nothing says we have to understand everything we're sent
+        let Some(extension) = self.local.get_mut(id as usize - 1) else {
+            return Ok(Vec::new());
+        };
+
+        let mut context = ExtensionContext {
+            swarm,
+            bitfield,
+            replies: Vec::new(),
+        };
+        extension
+            .message(&mut context, payload)
+            .with_context(|| format!("handling {} message", extension.name()))?;
+
+        let name = extension.name();
+        let Some(remote_id) = self.remote_id(name) else {
+            return Ok(Vec::new());
+        };
+
+        Ok(context
+            .replies
+            .into_iter()
+            .map(|payload| Message::Extended {
+                id: remote_id,
+                payload: payload.into(),
+            })
+            .collect())
+    }
+
+    // The id the peer wants messages for the named extension sent as, if it
+    // supports the extension at all
+    fn remote_id(&self, name: &str) -> Option<u8> {
+        let id = *self.remote.as_ref()?.m.get(name)?;
+        u8::try_from(id).ok().filter(|&id| id != HANDSHAKE_ID)
+    }
+}
@@ -0,0 +1,311 @@
+use anyhow::{Context, Result};
+
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// eMule lets through anything at this access level and above
+const EMULE_ALLOWED: u32 = 128;
+
+// Malformed lines warned about one by one per file, the rest only counted
+const MAX_WARNINGS: usize = 10;
+
+// Address ranges we never talk to, kept sorted and merged so a lookup is a
+// binary search
+#[derive(Debug, Default)]
+pub(crate) struct IpFilter {
+    ranges: Vec<(u32, u32)>,
+}
+
+static FILTER: OnceLock<IpFilter> = OnceLock::new();
+
+// Set once on startup, anything connecting or accepting asks for it
+pub(crate) fn configure(filter: IpFilter) {
+    FILTER
+        .set(filter)
+        .expect("IP filter should only be configured once");
+}
+
+pub(crate) fn is_blocked(ip: &Ipv4Addr) -> bool {
+    FILTER.get_or_init(IpFilter::default).blocks(ip)
+}
+
+impl IpFilter {
+    // Every line of every file is one of an eMule ipfilter.dat entry, a
+    // PeerGuardian P2P entry, a CIDR block or a single address
+    pub(crate) fn load(files: &[PathBuf]) -> Result<Self> {
+        let mut ranges = Vec::new();
+        for file in files {
+            ranges.extend(load_file(file).with_context(|| format!("loading {}", file.display()))?);
+        }
+
+        Ok(Self::from_ranges(ranges))
+    }
+
+    fn from_ranges(mut ranges: Vec<(u32, u32)>) -> Self {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(previous) if first <= previous.1.saturating_add(1) => {
+                    previous.1 = previous.1.max(last)
+                }
+                _ => merged.push((first, last)),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    pub(crate) fn blocks(&self, ip: &Ipv4Addr) -> bool {
+        let ip = u32::from(*ip);
+        // The last range starting at or before the address is the only one
+        // that can hold it
+        let after = self.ranges.partition_point(|&(first, _)| first <= ip);
+        after > 0 && ip <= self.ranges[after - 1].1
+    }
+}
+
+fn load_file(file: &Path) -> Result<Vec<(u32, u32)>> {
+    // Lists in the wild aren't always UTF-8 in their descriptions
+    let bytes = std::fs::read(file)?;
+    let text = String::from_utf8_lossy(&bytes);
+
+    let (ranges, skipped) = parse_list(&file.display().to_string(), &text);
+    if skipped > 0 {
+        eprintln!("{}: skipped {skipped} malformed lines", file.display());
+    }
+
+    Ok(ranges)
+}
+
+// A bad line shouldn't cost us the rest of a list that's otherwise fine, so
+// it's skipped and counted
+fn parse_list(name: &str, text: &str) -> (Vec<(u32, u32)>, usize) {
+    let mut ranges = Vec::new();
+    let mut skipped = 0;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(range) => ranges.extend(range),
+            Err(e) => {
+                if skipped < MAX_WARNINGS {
+                    eprintln!("{name}: skipping line {}: {e:#}", number + 1);
+                }
+                skipped += 1;
+            }
+        }
+    }
+
+    (ranges, skipped)
+}
+
+// None for entries that are in the list but let through
+fn parse_line(line: &str) -> Result<Option<(u32, u32)>> {
+    // eMule: first - last , level , description. P2P descriptions can have
+    // commas too, but then there's no range in front of the first one.
+    if let Some((Ok(range), rest)) = line
+        .split_once(',')
+        .map(|(range, rest)| (parse_range(range), rest))
+    {
+        let level = rest.split(',').next().unwrap_or_default().trim();
+        let level: u32 = level.parse().context("parsing access level")?;
+        return Ok((level < EMULE_ALLOWED).then_some(range));
+    }
+
+    // P2P: description:first-last, where the description may have colons
+    if let Some((_, range)) = line.rsplit_once(':') {
+        return parse_range(range).map(Some);
+    }
+
+    if let Some((address, bits)) = line.split_once('/') {
+        let first = parse_ip(address)?;
+        let bits: u32 = bits.trim().parse().context("parsing prefix length")?;
+        anyhow::ensure!(bits <= 32, "prefix length {bits} is too long");
+        let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+        return Ok(Some((first & mask, first | !mask)));
+    }
+
+    parse_range(line).map(Some)
+}
+
+fn parse_range(range: &str) -> Result<(u32, u32)> {
+    let (first, last) = match range.split_once('-') {
+        Some((first, last)) => (parse_ip(first)?, parse_ip(last)?),
+        None => {
+            let ip = parse_ip(range)?;
+            (ip, ip)
+        }
+    };
+    anyhow::ensure!(first <= last, "range ends before it starts");
+
+    Ok((first, last))
+}
+
+// ipfilter.dat pads every octet with zeros, which Ipv4Addr won't parse
+fn parse_ip(ip: &str) -> Result<u32> {
+    let octets: Vec<u8> = ip
+        .trim()
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("invalid address {}", ip.trim()))?;
+    anyhow::ensure!(octets.len() == 4, "invalid address {}", ip.trim());
+
+    Ok(u32::from_be_bytes([
+        octets[0], octets[1], octets[2], octets[3],
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> u32 {
+        u32::from(ip.parse::<Ipv4Addr>().unwrap())
+    }
+
+    fn range(first: &str, last: &str) -> Option<(u32, u32)> {
+        Some((ip(first), ip(last)))
+    }
+
+    #[test]
+    fn formats() {
+        let cases = [
+            // eMule
+            (
+                "001.002.003.000 - 001.002.003.255 , 000 , Some ISP",
+                range("1.2.3.0", "1.2.3.255"),
+            ),
+            (
+                "010.000.000.000-010.255.255.255,127,Private, or so",
+                range("10.0.0.0", "10.255.255.255"),
+            ),
+            ("1.2.3.0 - 1.2.3.255 , 128 , Let through", None),
+            ("1.2.3.0 - 1.2.3.255 , 200 , Let through", None),
+            // P2P
+            (
+                "Some Company: Inc.:4.5.6.0-4.5.6.127",
+                range("4.5.6.0", "4.5.6.127"),
+            ),
+            (
+                "Commas, even:7.8.9.10-7.8.9.10",
+                range("7.8.9.10", "7.8.9.10"),
+            ),
+            // CIDR
+            ("192.168.1.77/24", range("192.168.1.0", "192.168.1.255")),
+            ("8.8.8.8/32", range("8.8.8.8", "8.8.8.8")),
+            ("8.8.8.8/0", range("0.0.0.0", "255.255.255.255")),
+            // Plain
+            ("5.6.7.8", range("5.6.7.8", "5.6.7.8")),
+            ("5.6.7.8 - 5.6.8.0", range("5.6.7.8", "5.6.8.0")),
+        ];
+
+        for (line, expected) in cases {
+            assert_eq!(parse_line(line).unwrap(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for line in [
+            "1.2.3.0 - 1.2.3.255 , high , No level",
+            "Description:1.2.3-1.2.3.4",
+            "1.2.3.4/33",
+            "1.2.3.4/",
+            "1.2.3.256",
+            "1.2.3.4.5",
+            "5.6.7.8 - 1.2.3.4",
+            "nonsense",
+        ] {
+            assert!(parse_line(line).is_err(), "{line}");
+        }
+    }
+
+    #[test]
+    fn malformed_lines_skipped() {
+        let text = "\
+# comment
+// comment too
+
+1.2.3.4
+nonsense
+Bad:1.2.3.4-1.2.3
+5.6.7.0/24
+";
+        let (ranges, skipped) = parse_list("test", text);
+        assert_eq!(
+            ranges,
+            [
+                (ip("1.2.3.4"), ip("1.2.3.4")),
+                (ip("5.6.7.0"), ip("5.6.7.255"))
+            ]
+        );
+        assert_eq!(skipped, 2);
+    }
+
+    #[test]
+    fn merged() {
+        let filter = IpFilter::from_ranges(vec![
+            // Overlapping, out of order
+            (ip("10.0.0.50"), ip("10.0.0.200")),
+            (ip("10.0.0.0"), ip("10.0.0.100")),
+            // Contained
+            (ip("10.0.0.10"), ip("10.0.0.20")),
+            // Adjacent
+            (ip("10.0.0.201"), ip("10.0.1.0")),
+            // A gap of one address
+            (ip("10.0.1.2"), ip("10.0.1.2")),
+            // Up against the end of the address space
+            (ip("255.255.255.0"), ip("255.255.255.255")),
+            (ip("255.255.255.255"), ip("255.255.255.255")),
+        ]);
+        assert_eq!(
+            filter.ranges,
+            [
+                (ip("10.0.0.0"), ip("10.0.1.0")),
+                (ip("10.0.1.2"), ip("10.0.1.2")),
+                (ip("255.255.255.0"), ip("255.255.255.255")),
+            ]
+        );
+    }
+
+    #[test]
+    fn lookup_boundaries() {
+        let filter = IpFilter::from_ranges(vec![
+            (ip("0.0.0.0"), ip("0.0.0.10")),
+            (ip("10.0.0.0"), ip("10.0.0.255")),
+            (ip("10.0.2.0"), ip("10.0.2.0")),
+            (ip("255.255.255.250"), ip("255.255.255.255")),
+        ]);
+
+        let cases = [
+            ("0.0.0.0", true),
+            ("0.0.0.10", true),
+            ("0.0.0.11", false),
+            ("9.255.255.255", false),
+            ("10.0.0.0", true),
+            ("10.0.0.255", true),
+            ("10.0.1.0", false),
+            ("10.0.1.255", false),
+            ("10.0.2.0", true),
+            ("10.0.2.1", false),
+            ("255.255.255.249", false),
+            ("255.255.255.250", true),
+            ("255.255.255.255", true),
+        ];
+        for (address, blocked) in cases {
+            assert_eq!(
+                filter.blocks(&address.parse().unwrap()),
+                blocked,
+                "{address}"
+            );
+        }
+
+        assert!(!IpFilter::default().blocks(&Ipv4Addr::UNSPECIFIED));
+        assert!(!IpFilter::default().blocks(&Ipv4Addr::BROADCAST));
+    }
+}
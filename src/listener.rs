@@ -0,0 +1,213 @@
+use anyhow::{Context, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::ipfilter;
+use crate::mse::{self, Encryption};
+use crate::peer::{peer_id, Handshake, Peer};
+use crate::swarm::Swarm;
+use crate::trace::{Direction, Trace};
+use crate::transport::{Stream, Transport};
+use crate::utp::UtpSocket;
+
+// Plaintext connections start with the protocol name, anything else is taken
+// to be the start of an encryption handshake
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+// For everything up to and including our handshake reply, encryption too
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+// Incoming peers served at once, anyone past that is turned away
+const MAX_INBOUND: usize = 200;
+// Accepting fails now and then, out of file descriptors or a peer hanging up
+// before we got to it. Only failing over and over means the socket is broken.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_FAILURES: u32 = 100;
+
+// Accepts incoming peers for any of the torrents we are working on, over TCP
+// and uTP on the same port
+pub(crate) struct Listener {
+    listener: TcpListener,
+    utp: Arc<UtpSocket>,
+    port: u16,
+    encryption: Encryption,
+    torrents: HashMap<[u8; 20], Arc<Swarm>>,
+    // One permit for every incoming peer being served
+    slots: Arc<Semaphore>,
+}
+
+impl Listener {
+    pub(crate) async fn bind(port: u16, encryption: Encryption) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .await
+            .with_context(|| format!("listening on port {port}"))?;
+        let utp = UtpSocket::bind(port).await?;
+
+        Ok(Self {
+            listener,
+            utp,
+            port,
+            encryption,
+            torrents: HashMap::new(),
+            slots: Arc::new(Semaphore::new(MAX_INBOUND)),
+        })
+    }
+
+    pub(crate) fn utp(&self) -> Arc<UtpSocket> {
+        self.utp.clone()
+    }
+
+    pub(crate) fn add(&mut self, info_hash: [u8; 20], swarm: Arc<Swarm>) {
+        swarm.set_port(self.port);
+        self.torrents.insert(info_hash, swarm);
+    }
+
+    pub(crate) async fn run(self) -> Result<()> {
+        let torrents = Arc::new(self.torrents);
+        let encryption = self.encryption;
+        let mut failures = 0;
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (stream, address) = match accepted {
+                        Ok(accepted) => {
+                            failures = 0;
+                            accepted
+                        }
+                        Err(e) => {
+                            failures += 1;
+                            if failures >= MAX_ACCEPT_FAILURES {
+                                return Err(e).context("accepting connection");
+                            }
+                            eprintln!("accepting connection failed, trying again: {e}");
+                            time::sleep(ACCEPT_BACKOFF * failures.min(10)).await;
+                            continue;
+                        }
+                    };
+                    // Peers are IPv4 only everywhere else too
+                    let SocketAddr::V4(address) = address else {
+                        continue;
+                    };
+                    if ipfilter::is_blocked(address.ip()) {
+                        continue;
+                    }
+                    // Full up, the connection is dropped right away
+                    let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+                        continue;
+                    };
+                    spawn_serve(stream, address, torrents.clone(), encryption, slot);
+                }
+                accepted = self.utp.accept() => {
+                    let (stream, address) = accepted.context("accepting uTP connection")?;
+                    if ipfilter::is_blocked(address.ip()) {
+                        continue;
+                    }
+                    // Full up, the connection is dropped right away
+                    let Ok(slot) = self.slots.clone().try_acquire_owned() else {
+                        continue;
+                    };
+                    spawn_serve(stream, address, torrents.clone(), encryption, slot);
+                }
+            }
+        }
+    }
+}
+
+fn spawn_serve<S: Stream>(
+    stream: S,
+    address: SocketAddrV4,
+    torrents: Arc<HashMap<[u8; 20], Arc<Swarm>>>,
+    encryption: Encryption,
+    slot: OwnedSemaphorePermit,
+) {
+    tokio::spawn(async move {
+        // A peer going away is business as usual, nothing to report
+        let _ = serve(stream, address, &torrents, encryption).await;
+        drop(slot);
+    });
+}
+
+async fn serve<S: Stream>(
+    stream: S,
+    address: SocketAddrV4,
+    torrents: &HashMap<[u8; 20], Arc<Swarm>>,
+    encryption: Encryption,
+) -> Result<()> {
+    let (transport, handshake, swarm) = time::timeout(
+        HANDSHAKE_TIMEOUT,
+        handshake(stream, address, torrents, encryption),
+    )
+    .await
+    .context("peer took too long to handshake")??;
+
+    let mut peer = Peer::from_stream(
+        address,
+        transport,
+        &handshake,
+        &swarm.advertised(),
+        swarm.throttle(),
+    )
+    .await?;
+    peer.run(&swarm).await
+}
+
+// Both handshakes, encryption included when the peer asks for it
+async fn handshake<S: Stream>(
+    mut stream: S,
+    address: SocketAddrV4,
+    torrents: &HashMap<[u8; 20], Arc<Swarm>>,
+    encryption: Encryption,
+) -> Result<(Transport<S>, Handshake, Arc<Swarm>)> {
+    let mut prefix = vec![0; PROTOCOL_HEADER.len()];
+    stream
+        .read_exact(&mut prefix)
+        .await
+        .context("receiving handshake")?;
+
+    let (mut transport, encrypted_for) = if prefix == PROTOCOL_HEADER {
+        anyhow::ensure!(
+            encryption != Encryption::Require,
+            "refusing plaintext connection"
+        );
+        (Transport::new(stream, None, prefix), None)
+    } else {
+        anyhow::ensure!(
+            encryption != Encryption::Disable,
+            "refusing encrypted connection"
+        );
+        let info_hashes: Vec<[u8; 20]> = torrents.keys().copied().collect();
+        let (transport, info_hash) = mse::accept(stream, prefix, &info_hashes, encryption).await?;
+        (transport, Some(info_hash))
+    };
+
+    let mut bytes = [0; Handshake::LENGTH];
+    transport
+        .read_exact(&mut bytes)
+        .await
+        .context("receiving handshake")?;
+    let handshake = Handshake::from_bytes(&bytes)?;
+    let trace = Trace::new(address);
+    trace.handshake(Direction::Received, &handshake);
+
+    let info_hash = handshake.info_hash;
+    let swarm = torrents
+        .get(&info_hash)
+        .with_context(|| format!("unknown info hash {}", hex::encode(info_hash)))?;
+    handshake.validate(&encrypted_for.unwrap_or(info_hash))?;
+    anyhow::ensure!(!swarm.is_banned(address.ip()), "peer is banned");
+
+    let reply = Handshake::new(info_hash, *peer_id());
+    trace.handshake(Direction::Sent, &reply);
+    transport
+        .write_all(&reply.to_bytes())
+        .await
+        .context("sending handshake")?;
+    transport.flush().await.context("sending handshake")?;
+
+    Ok((transport, handshake, swarm.clone()))
+}
@@ -1,10 +1,33 @@
+mod bitfield;
+mod choker;
+mod client;
 mod commands;
+mod connector;
+mod extensions;
+mod ipfilter;
+mod listener;
+mod mse;
 mod peer;
+mod picker;
+mod proxy;
+mod ratelimit;
+mod rng;
+mod stats;
+mod storage;
+mod superseed;
+mod swarm;
 mod torrent;
+mod trace;
 mod tracker;
+mod transport;
+mod utp;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
+use ipfilter::IpFilter;
+use mse::Encryption;
+use proxy::Proxy;
+use ratelimit::RateLimits;
 
 use std::path::PathBuf;
 
@@ -13,8 +36,19 @@ use std::path::PathBuf;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    // Everything going out goes through this, socks5:// or http://
+    #[arg(long, global = true, value_parser = Proxy::parse)]
+    proxy: Option<Proxy>,
+    // Blocklists of addresses never to connect to or accept, in eMule
+    // ipfilter.dat, PeerGuardian P2P or CIDR format
+    #[arg(long, global = true)]
+    ip_filter: Vec<PathBuf>,
+    // Log every handshake and message to this file, one JSON object a line
+    #[arg(long, global = true)]
+    trace: Option<PathBuf>,
 }
 
+// Subcommands are snake_case, their flags kebab-case like the global ones
 #[derive(Subcommand, Debug)]
 #[clap(rename_all = "snake_case")]
 enum Commands {
@@ -26,6 +60,9 @@ enum Commands {
     },
     Peers {
         file: PathBuf,
+        // Handshake with every peer to find out what client it runs
+        #[arg(long)]
+        clients: bool,
     },
     Handshake {
         file: PathBuf,
@@ -36,17 +73,56 @@ enum Commands {
         output: PathBuf,
         torrent: PathBuf,
         piece: usize,
+        #[arg(long = "max-requests", default_value_t = peer::DEFAULT_MAX_REQUESTS)]
+        max_requests: usize,
+        #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+        encryption: Encryption,
+        // Try peers over uTP before TCP
+        #[arg(long)]
+        utp: bool,
+        #[command(flatten)]
+        limits: RateLimits,
     },
     Download {
         #[arg(short)]
         output: PathBuf,
         torrent: PathBuf,
+        #[arg(long = "max-requests", default_value_t = peer::DEFAULT_MAX_REQUESTS)]
+        max_requests: usize,
+        #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+        encryption: Encryption,
+        #[arg(long)]
+        utp: bool,
+        #[command(flatten)]
+        limits: RateLimits,
+        // Print per-peer numbers every few seconds while downloading
+        #[arg(long)]
+        stats: bool,
+    },
+    Seed {
+        torrent: PathBuf,
+        data: PathBuf,
+        #[arg(long, default_value_t = tracker::PORT)]
+        port: u16,
+        #[arg(long = "upload-slots", default_value_t = choker::DEFAULT_UPLOAD_SLOTS)]
+        upload_slots: usize,
+        #[arg(long, value_enum, default_value_t = Encryption::Prefer)]
+        encryption: Encryption,
+        #[command(flatten)]
+        limits: RateLimits,
+        // Hand out pieces one at a time to get a new torrent out from a
+        // single seed with as little uploading as possible
+        #[arg(long = "super-seed")]
+        super_seed: bool,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    proxy::configure(cli.proxy);
+    ipfilter::configure(IpFilter::load(&cli.ip_filter).context("loading IP filter")?);
+    trace::configure(cli.trace.as_deref())?;
 
     match cli.command {
         Commands::Decode { value } => {
@@ -55,7 +131,7 @@ async fn main() -> Result<()> {
             println!("{output}");
         }
         Commands::Info { file } => commands::info::invoke(file).context("parsing torrent info")?,
-        Commands::Peers { file } => commands::peers::invoke(file)
+        Commands::Peers { file, clients } => commands::peers::invoke(file, clients)
             .await
             .context("getting torrent peers")?,
         Commands::Handshake { file, peer } => commands::handshake::invoke(file, peer)
@@ -66,13 +142,61 @@ async fn main() -> Result<()> {
             output,
             torrent,
             piece,
-        } => commands::download::piece(output, torrent, piece)
-            .await
-            .context("downloading piece")?,
+            max_requests,
+            encryption,
+            utp,
+            limits,
+        } => commands::download::piece(
+            output,
+            torrent,
+            piece,
+            max_requests,
+            encryption,
+            utp,
+            limits,
+        )
+        .await
+        .context("downloading piece")?,
 
-        Commands::Download { output, torrent } => commands::download::full(output, torrent)
-            .await
-            .context("downloading full file")?,
+        Commands::Download {
+            output,
+            torrent,
+            max_requests,
+            encryption,
+            utp,
+            limits,
+            stats,
+        } => commands::download::full(
+            output,
+            torrent,
+            max_requests,
+            encryption,
+            utp,
+            limits,
+            stats,
+        )
+        .await
+        .context("downloading full file")?,
+
+        Commands::Seed {
+            torrent,
+            data,
+            port,
+            upload_slots,
+            encryption,
+            limits,
+            super_seed,
+        } => commands::seed::invoke(
+            torrent,
+            data,
+            port,
+            upload_slots,
+            encryption,
+            limits,
+            super_seed,
+        )
+        .await
+        .context("seeding torrent")?,
     }
 
     Ok(())
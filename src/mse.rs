@@ -0,0 +1,712 @@
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+
+use std::time::Duration;
+
+use crate::rng::secure_bytes;
+use crate::transport::{Cipher, Stream, Transport};
+
+// Message Stream Encryption, the Diffie-Hellman handshake followed by RC4 that
+// gets connections past ISPs throttling plaintext BitTorrent. Described at
+// https://wiki.vuze.com/w/Message_Stream_Encryption
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Encryption {
+    // Only ever talk to peers over RC4
+    Require,
+    // Try RC4 first, but fall back to plaintext for peers that can't do it
+    Prefer,
+    // Plaintext only, like it used to be
+    Disable,
+}
+
+// 768 bit safe prime everyone uses, with a generator of 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u64 = 2;
+const KEY_LENGTH: usize = 96;
+// 160 bits of private key is what the spec asks for
+const PRIVATE_KEY_LENGTH: usize = 20;
+const MAX_PADDING: usize = 512;
+const VERIFICATION_CONSTANT: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// Peers that don't speak MSE usually hang up straight away, but not always
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Sets up encryption on a connection we made, for the torrent with `info_hash`
+pub(crate) async fn initiate<S: Stream>(
+    stream: S,
+    info_hash: &[u8; 20],
+    encryption: Encryption,
+) -> Result<Transport<S>> {
+    time::timeout(
+        HANDSHAKE_TIMEOUT,
+        initiate_inner(stream, info_hash, encryption),
+    )
+    .await
+    .context("encryption handshake timed out")?
+}
+
+async fn initiate_inner<S: Stream>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    encryption: Encryption,
+) -> Result<Transport<S>> {
+    let (private, public) = key_pair()?;
+    stream
+        .write_all(&[public.as_slice(), &padding()?].concat())
+        .await
+        .context("sending public key")?;
+
+    let mut remote = [0; KEY_LENGTH];
+    stream
+        .read_exact(&mut remote)
+        .await
+        .context("receiving public key")?;
+    let secret = shared_secret(&private, &remote)?;
+
+    let mut outgoing = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut incoming = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let provide = match encryption {
+        Encryption::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+
+    // No initial payload, the BitTorrent handshake follows once we know
+    // which way it is going to be sent
+    let pad = padding()?;
+    let mut encrypted = VERIFICATION_CONSTANT.to_vec();
+    encrypted.extend_from_slice(&provide.to_be_bytes());
+    encrypted.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    encrypted.extend_from_slice(&pad);
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    outgoing.apply(&mut encrypted);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    message.extend_from_slice(&encrypted);
+    stream
+        .write_all(&message)
+        .await
+        .context("sending crypto offer")?;
+
+    // Their padding is in the way, the encrypted verification constant marks
+    // where it ends
+    let mut marker = VERIFICATION_CONSTANT;
+    incoming.apply(&mut marker);
+    let mut reader = Reader::new(stream, Vec::new());
+    reader
+        .sync(&marker)
+        .await
+        .context("looking for the verification constant")?;
+
+    let mut header = [0; 6];
+    reader.read_exact(&mut header).await?;
+    incoming.apply(&mut header);
+    let select = u32::from_be_bytes(header[..4].try_into().expect("4 bytes"));
+    let pad_length = u16::from_be_bytes(header[4..].try_into().expect("2 bytes")) as usize;
+    anyhow::ensure!(
+        pad_length <= MAX_PADDING,
+        "padding is {pad_length} bytes long"
+    );
+
+    let mut pad = vec![0; pad_length];
+    reader.read_exact(&mut pad).await?;
+    incoming.apply(&mut pad);
+
+    anyhow::ensure!(
+        select.count_ones() == 1 && select & provide != 0,
+        "peer selected crypto method {select:#x} when offered {provide:#x}"
+    );
+
+    let cipher = (select == CRYPTO_RC4).then_some(Cipher { incoming, outgoing });
+    Ok(reader.finish(cipher, Vec::new()))
+}
+
+// Sets up encryption on a connection someone made to us, `prefix` being
+// whatever was already read off it. Returns the info hash they're after.
+pub(crate) async fn accept<S: Stream>(
+    stream: S,
+    prefix: Vec<u8>,
+    info_hashes: &[[u8; 20]],
+    encryption: Encryption,
+) -> Result<(Transport<S>, [u8; 20])> {
+    time::timeout(
+        HANDSHAKE_TIMEOUT,
+        accept_inner(stream, prefix, info_hashes, encryption),
+    )
+    .await
+    .context("encryption handshake timed out")?
+}
+
+async fn accept_inner<S: Stream>(
+    stream: S,
+    prefix: Vec<u8>,
+    info_hashes: &[[u8; 20]],
+    encryption: Encryption,
+) -> Result<(Transport<S>, [u8; 20])> {
+    let mut reader = Reader::new(stream, prefix);
+    let mut remote = [0; KEY_LENGTH];
+    reader
+        .read_exact(&mut remote)
+        .await
+        .context("receiving public key")?;
+
+    let (private, public) = key_pair()?;
+    reader
+        .stream
+        .write_all(&[public.as_slice(), &padding()?].concat())
+        .await
+        .context("sending public key")?;
+    let secret = shared_secret(&private, &remote)?;
+
+    reader
+        .sync(&hash(&[b"req1", &secret]))
+        .await
+        .context("looking for the start of the crypto offer")?;
+
+    // Which torrent they want is hidden behind a hash of its info hash
+    let mut obfuscated = [0; 20];
+    reader.read_exact(&mut obfuscated).await?;
+    let wanted = xor(&obfuscated, &hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == wanted)
+        .context("peer wants a torrent we don't have")?;
+
+    let mut incoming = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut outgoing = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut header = [0; 14];
+    reader.read_exact(&mut header).await?;
+    incoming.apply(&mut header);
+    anyhow::ensure!(
+        header[..8] == VERIFICATION_CONSTANT,
+        "wrong verification constant"
+    );
+    let provide = u32::from_be_bytes(header[8..12].try_into().expect("4 bytes"));
+    let pad_length = u16::from_be_bytes(header[12..].try_into().expect("2 bytes")) as usize;
+    anyhow::ensure!(
+        pad_length <= MAX_PADDING,
+        "padding is {pad_length} bytes long"
+    );
+
+    // The padding, followed by the length of the initial payload
+    let mut pad = vec![0; pad_length + 2];
+    reader.read_exact(&mut pad).await?;
+    incoming.apply(&mut pad);
+    let initial_length =
+        u16::from_be_bytes(pad[pad_length..].try_into().expect("2 bytes")) as usize;
+
+    let mut initial = vec![0; initial_length];
+    reader.read_exact(&mut initial).await?;
+    incoming.apply(&mut initial);
+
+    // With encryption disabled the obfuscated handshake is as far as it goes
+    let select = match encryption {
+        Encryption::Disable if provide & CRYPTO_PLAINTEXT != 0 => CRYPTO_PLAINTEXT,
+        Encryption::Require | Encryption::Prefer if provide & CRYPTO_RC4 != 0 => CRYPTO_RC4,
+        Encryption::Prefer if provide & CRYPTO_PLAINTEXT != 0 => CRYPTO_PLAINTEXT,
+        _ => anyhow::bail!("peer offered crypto methods {provide:#x}, none of which we accept"),
+    };
+
+    let pad = padding()?;
+    let mut reply = VERIFICATION_CONSTANT.to_vec();
+    reply.extend_from_slice(&select.to_be_bytes());
+    reply.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&pad);
+    outgoing.apply(&mut reply);
+    reader
+        .stream
+        .write_all(&reply)
+        .await
+        .context("sending crypto selection")?;
+
+    let cipher = (select == CRYPTO_RC4).then_some(Cipher { incoming, outgoing });
+    Ok((reader.finish(cipher, initial), info_hash))
+}
+
+// Reads off the socket during the handshake, holding on to anything read past
+// what was asked for
+struct Reader<S> {
+    stream: S,
+    buffered: Vec<u8>,
+}
+
+impl<S: Stream> Reader<S> {
+    fn new(stream: S, buffered: Vec<u8>) -> Self {
+        Self { stream, buffered }
+    }
+
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let from_buffer = buffer.len().min(self.buffered.len());
+        buffer[..from_buffer].copy_from_slice(&self.buffered[..from_buffer]);
+        self.buffered.drain(..from_buffer);
+
+        self.stream
+            .read_exact(&mut buffer[from_buffer..])
+            .await
+            .context("reading encryption handshake")?;
+
+        Ok(())
+    }
+
+    // Skips up to the padding limit until just past `marker`
+    async fn sync(&mut self, marker: &[u8]) -> Result<()> {
+        let limit = MAX_PADDING + marker.len();
+        loop {
+            if let Some(at) = self
+                .buffered
+                .windows(marker.len())
+                .position(|window| window == marker)
+            {
+                self.buffered.drain(..at + marker.len());
+                return Ok(());
+            }
+
+            anyhow::ensure!(self.buffered.len() < limit, "no sign of the marker");
+
+            let mut chunk = [0; 256];
+            let read = self
+                .stream
+                .read(&mut chunk[..(limit - self.buffered.len()).min(256)])
+                .await
+                .context("reading encryption handshake")?;
+            anyhow::ensure!(read > 0, "peer closed the connection");
+            self.buffered.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    // Anything read past the handshake is payload, to be decrypted like the
+    // rest of it and handed out after the `initial` payload
+    fn finish(mut self, mut cipher: Option<Cipher>, mut initial: Vec<u8>) -> Transport<S> {
+        if let Some(cipher) = &mut cipher {
+            cipher.incoming.apply(&mut self.buffered);
+        }
+        initial.extend_from_slice(&self.buffered);
+
+        Transport::new(self.stream, cipher, initial)
+    }
+}
+
+pub(crate) struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        // The start of the keystream is weak, MSE throws the first KiB away
+        let mut rc4 = Self { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    pub(crate) fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|index| a[index] ^ b[index])
+}
+
+fn padding() -> Result<Vec<u8>> {
+    let mut length = [0; 2];
+    secure_bytes(&mut length)?;
+    let mut pad = vec![0; u16::from_be_bytes(length) as usize % (MAX_PADDING + 1)];
+    secure_bytes(&mut pad)?;
+    Ok(pad)
+}
+
+fn key_pair() -> Result<(Vec<u8>, [u8; KEY_LENGTH])> {
+    let mut private = vec![0; PRIVATE_KEY_LENGTH];
+    secure_bytes(&mut private)?;
+
+    let public = to_bytes(&prime().pow(&small(GENERATOR), &private));
+
+    Ok((private, public))
+}
+
+fn shared_secret(private: &[u8], remote: &[u8; KEY_LENGTH]) -> Result<[u8; KEY_LENGTH]> {
+    let prime = prime();
+    let remote = from_bytes(remote);
+
+    // Keys like 1 or p-1 would give away the secret
+    let mut upper = prime.n;
+    subtract(&mut upper, &small(1));
+    anyhow::ensure!(
+        compare(&remote, &small(1)).is_gt() && compare(&remote, &upper).is_lt(),
+        "peer sent a degenerate public key"
+    );
+
+    Ok(to_bytes(&prime.pow(&remote, private)))
+}
+
+// Just enough big number arithmetic for the key exchange. Numbers are 768 bits
+// as little endian 64 bit limbs, multiplied in Montgomery form.
+const LIMBS: usize = KEY_LENGTH / 8;
+
+type Number = [u64; LIMBS];
+
+fn prime() -> Modulus {
+    Modulus::new(&from_bytes(
+        &hex::decode(PRIME).expect("prime is valid hex"),
+    ))
+}
+
+fn small(value: u64) -> Number {
+    let mut number = [0; LIMBS];
+    number[0] = value;
+    number
+}
+
+struct Modulus {
+    n: Number,
+    // -n^-1 mod 2^64
+    inverse: u64,
+    // 2^(2 * 768) mod n, for getting into Montgomery form
+    r2: Number,
+}
+
+impl Modulus {
+    fn new(n: &Number) -> Self {
+        // Newton's method doubles the correct bits every round
+        let mut inverse = 1u64;
+        for _ in 0..6 {
+            inverse = inverse.wrapping_mul(2u64.wrapping_sub(n[0].wrapping_mul(inverse)));
+        }
+
+        let mut r2 = [0; LIMBS];
+        r2[0] = 1;
+        for _ in 0..2 * LIMBS * 64 {
+            let carry = shift_left(&mut r2);
+            if carry || compare(&r2, n).is_ge() {
+                subtract(&mut r2, n);
+            }
+        }
+
+        Self {
+            n: *n,
+            inverse: inverse.wrapping_neg(),
+            r2,
+        }
+    }
+
+    // a * b / 2^768 mod n
+    fn multiply(&self, a: &Number, b: &Number) -> Number {
+        let mut t = [0u64; LIMBS + 2];
+        for &a in a {
+            let mut carry = 0u128;
+            for j in 0..LIMBS {
+                let sum = t[j] as u128 + a as u128 * b[j] as u128 + carry;
+                t[j] = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[LIMBS] as u128 + carry;
+            t[LIMBS] = sum as u64;
+            t[LIMBS + 1] = (sum >> 64) as u64;
+
+            let m = t[0].wrapping_mul(self.inverse);
+            let mut carry = (t[0] as u128 + m as u128 * self.n[0] as u128) >> 64;
+            for j in 1..LIMBS {
+                let sum = t[j] as u128 + m as u128 * self.n[j] as u128 + carry;
+                t[j - 1] = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[LIMBS] as u128 + carry;
+            t[LIMBS - 1] = sum as u64;
+            t[LIMBS] = t[LIMBS + 1] + (sum >> 64) as u64;
+        }
+
+        let mut result: Number = t[..LIMBS].try_into().expect("limbs");
+        if t[LIMBS] != 0 || compare(&result, &self.n).is_ge() {
+            subtract(&mut result, &self.n);
+        }
+        result
+    }
+
+    // base^exponent mod n, the exponent being big endian bytes
+    fn pow(&self, base: &Number, exponent: &[u8]) -> Number {
+        let base = self.multiply(base, &self.r2);
+        let one = small(1);
+        let mut result = self.multiply(&one, &self.r2);
+
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = self.multiply(&result, &result);
+                if byte >> bit & 1 == 1 {
+                    result = self.multiply(&result, &base);
+                }
+            }
+        }
+
+        self.multiply(&result, &one)
+    }
+}
+
+fn from_bytes(bytes: &[u8]) -> Number {
+    let mut number = [0; LIMBS];
+    for (limb, chunk) in number.iter_mut().zip(bytes.rchunks(8)) {
+        let mut word = [0; 8];
+        word[8 - chunk.len()..].copy_from_slice(chunk);
+        *limb = u64::from_be_bytes(word);
+    }
+    number
+}
+
+fn to_bytes(number: &Number) -> [u8; KEY_LENGTH] {
+    let mut bytes = [0; KEY_LENGTH];
+    for (chunk, limb) in bytes.rchunks_mut(8).zip(number) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn compare(a: &Number, b: &Number) -> std::cmp::Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+fn shift_left(number: &mut Number) -> bool {
+    let mut carry = 0;
+    for limb in number.iter_mut() {
+        let next = *limb >> 63;
+        *limb = *limb << 1 | carry;
+        carry = next;
+    }
+    carry == 1
+}
+
+fn subtract(a: &mut Number, b: &Number) {
+    let mut borrow = false;
+    for (a, &b) in a.iter_mut().zip(b) {
+        let (difference, overflow) = a.overflowing_sub(b);
+        let (difference, overflow2) = difference.overflowing_sub(borrow as u64);
+        *a = difference;
+        borrow = overflow || overflow2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    const EXPONENT: &str = "0123456789abcdeffedcba987654321000112233";
+
+    fn number(hex: &str) -> Number {
+        from_bytes(&hex::decode(hex).unwrap())
+    }
+
+    #[test]
+    fn pow() {
+        let prime = prime();
+        let exponent = hex::decode(EXPONENT).unwrap();
+
+        assert_eq!(prime.pow(&small(2), &[10]), small(1024));
+        assert_eq!(prime.pow(&small(7), &[]), small(1));
+
+        // Generated with Python's pow(base, exponent, prime)
+        assert_eq!(
+            hex::encode(to_bytes(&prime.pow(&small(GENERATOR), &exponent))),
+            "318dff06cb274e6abdbaa031b12c6202ded6982564fe5c0c8584530ee101060f477d2e7d3a7bae990a6022098bb1f4bc967268614f1f31f07af1a98187e5d6ba104561f5803b5386fc7c769821c98f661688f3cb776d64ed8a98cfde8bb3f038"
+        );
+        assert_eq!(
+            hex::encode(to_bytes(
+                &prime.pow(&number("1234567890abcdef1122334455667788"), &exponent)
+            )),
+            "cba849c071f69650af120136462aa688ce0bf6c6b71caaed1f42ab2cee8e53ae47c92bb12948cf5913521cb7de594d17f3f33af6e99965434b1738a576cdb1b267ab9d04bbdf8d45d5819c104fdc8c851861a492b6fac8a6c330fa310754022c"
+        );
+        let mut base = prime.n;
+        subtract(&mut base, &small(2));
+        assert_eq!(
+            hex::encode(to_bytes(&prime.pow(&base, &exponent))),
+            "ce7200f934d8b1950b553a70703c6031e5efca661bddc0c4a37dfaf9a966c664ba8e90290097ec8946e9e670028210215922b1527e1b112ab53960ec6a793d7d3f9bd377ed166ebee8093ede4094ef60ddc34f1e2eccd133756730217455152b"
+        );
+
+        // Fermat, the prime being prime
+        let mut exponent = prime.n;
+        subtract(&mut exponent, &small(1));
+        assert_eq!(prime.pow(&small(3), &to_bytes(&exponent)), small(1));
+    }
+
+    #[test]
+    fn key_agreement() {
+        let (ours, our_public) = key_pair().unwrap();
+        let (theirs, their_public) = key_pair().unwrap();
+        assert_eq!(
+            shared_secret(&ours, &their_public).unwrap(),
+            shared_secret(&theirs, &our_public).unwrap()
+        );
+
+        assert!(shared_secret(&ours, &to_bytes(&small(1))).is_err());
+        assert!(shared_secret(&ours, &to_bytes(&prime().n)).is_err());
+    }
+
+    #[test]
+    fn rc4() {
+        // RFC 6229, 40 bit key, keystream from offset 1024 on since the first
+        // KiB is thrown away
+        let mut rc4 = Rc4::new(&[0x01, 0x02, 0x03, 0x04, 0x05]);
+        let mut keystream = [0; 32];
+        rc4.apply(&mut keystream);
+        assert_eq!(
+            hex::encode(keystream),
+            "30abbcc7c20b01609f23ee2d5f6bb7df73262dec31a8a8ff5f9f977001c90b72"
+        );
+
+        // Applying it twice gets the data back
+        let mut data = *b"BitTorrent protocol";
+        Rc4::new(b"key").apply(&mut data);
+        assert_ne!(&data, b"BitTorrent protocol");
+        Rc4::new(b"key").apply(&mut data);
+        assert_eq!(&data, b"BitTorrent protocol");
+    }
+
+    // Two ends of a connection, with everything passing between them copied
+    // into the returned tasks' results
+    fn tapped() -> (
+        DuplexStream,
+        DuplexStream,
+        JoinHandle<Vec<u8>>,
+        JoinHandle<Vec<u8>>,
+    ) {
+        let (a, tap_a) = tokio::io::duplex(1 << 16);
+        let (b, tap_b) = tokio::io::duplex(1 << 16);
+        let (a_read, a_write) = tokio::io::split(tap_a);
+        let (b_read, b_write) = tokio::io::split(tap_b);
+        (
+            a,
+            b,
+            tokio::spawn(tap(a_read, b_write)),
+            tokio::spawn(tap(b_read, a_write)),
+        )
+    }
+
+    async fn tap(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin) -> Vec<u8> {
+        let mut seen = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let read = from.read(&mut chunk).await.unwrap_or(0);
+            if read == 0 || to.write_all(&chunk[..read]).await.is_err() {
+                return seen;
+            }
+            seen.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback() {
+        use Encryption::*;
+
+        let info_hash = [7; 20];
+        let torrents = [[8; 20], info_hash];
+        // Initiator, acceptor and whether what follows is encrypted
+        let cases = [
+            (Require, Require, true),
+            (Require, Prefer, true),
+            (Prefer, Require, true),
+            (Prefer, Prefer, true),
+            (Prefer, Disable, false),
+            (Disable, Disable, false),
+        ];
+        for (ours, theirs, encrypted) in cases {
+            let (a, b, outgoing, incoming) = tapped();
+
+            let (initiated, accepted) = tokio::join!(
+                initiate(a, &info_hash, ours),
+                accept(b, Vec::new(), &torrents, theirs)
+            );
+            let mut initiator = initiated.unwrap();
+            let (mut acceptor, wanted) = accepted.unwrap();
+            assert_eq!(wanted, info_hash);
+
+            initiator.write_all(b"hello from a").await.unwrap();
+            initiator.flush().await.unwrap();
+            let mut hello = [0; 12];
+            acceptor.read_exact(&mut hello).await.unwrap();
+            assert_eq!(&hello, b"hello from a", "{ours:?} to {theirs:?}");
+
+            acceptor.write_all(b"hello from b").await.unwrap();
+            acceptor.flush().await.unwrap();
+            initiator.read_exact(&mut hello).await.unwrap();
+            assert_eq!(&hello, b"hello from b", "{ours:?} to {theirs:?}");
+
+            // Anyone watching sees the plaintext only if RC4 was turned down
+            drop((initiator, acceptor));
+            let outgoing = outgoing.await.unwrap();
+            let incoming = incoming.await.unwrap();
+            assert_eq!(
+                outgoing.windows(12).any(|window| window == b"hello from a"),
+                !encrypted,
+                "{ours:?} to {theirs:?}"
+            );
+            assert_eq!(
+                incoming.windows(12).any(|window| window == b"hello from b"),
+                !encrypted,
+                "{ours:?} to {theirs:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn require_meets_disable() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let torrents = [[7; 20]];
+        let (initiated, accepted) = tokio::join!(
+            initiate(a, &[7; 20], Encryption::Require),
+            accept(b, Vec::new(), &torrents, Encryption::Disable)
+        );
+        let error = accepted.err().unwrap();
+        assert!(error.to_string().contains("none of which we accept"));
+        assert!(initiated.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_torrent() {
+        let (a, b) = tokio::io::duplex(1 << 16);
+        let torrents = [[8; 20]];
+        // The acceptor hanging up is what lets the initiator give up
+        let (initiated, accepted) = tokio::join!(
+            initiate(a, &[7; 20], Encryption::Prefer),
+            accept(b, Vec::new(), &torrents, Encryption::Prefer)
+        );
+        let error = accepted.err().unwrap();
+        assert!(error.to_string().contains("torrent we don't have"));
+        assert!(initiated.is_err());
+    }
+}
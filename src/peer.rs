@@ -1,354 +1,1590 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
 use std::net::SocketAddrV4;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures_util::{SinkExt, StreamExt};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    sync::mpsc,
+    time,
 };
 use tokio_util::codec::{Decoder, Encoder, Framed};
 
-const BLOCK_SIZE: usize = 1 << 14;
-const MAX: usize = 1 << 16;
+use crate::bitfield::Bitfield;
+use crate::client::Client;
+use crate::extensions::{ExtendedHandshake, Extensions};
+use crate::picker::{Block, BlockOutcome, BLOCK_SIZE};
+use crate::ratelimit::{Throttle, Throttled};
+use crate::rng::Rng;
+use crate::stats::PeerStats;
+use crate::swarm::{PeerCommand, PeerHandle, Swarm};
+use crate::trace::{Direction, Trace};
+use crate::transport::{Connection, Dialer, Stream, Transport};
 
-#[allow(dead_code)]
-#[repr(u8)]
+// Most clients never ask for more than a 16 KiB block, but some go up to 128 KiB
+const MAX_REQUEST_LENGTH: usize = 1 << 17;
+// Enough for the largest piece message we are willing to send or receive
+const MAX: usize = MAX_REQUEST_LENGTH + 9;
+
+pub(crate) const DEFAULT_MAX_REQUESTS: usize = 64;
+const MIN_REQUESTS: usize = 2;
+// Keep enough requests in flight to cover this many seconds at the current rate
+const REQUEST_QUEUE_SECS: f64 = 2.0;
+// Requests from the peer we are willing to have queued up at once
+const MAX_QUEUED_UPLOADS: usize = 250;
+
+// Reserved bit 20, counting from the right, says we speak the extension protocol
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+// And bit 2 the fast extension, bit 0 DHT
+const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+const DHT: (usize, u8) = (7, 0x01);
+// Pieces a peer may download from us while choked, the spec suggests 10
+const ALLOWED_FAST_PIECES: usize = 10;
+
+// Peers drop connections that stay silent for two minutes, so we say
+// something well before that
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+// A peer sitting on our requests for this long without sending a single block
+// is snubbing us
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+// Everything that goes over the wire once the handshake is done
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum MessageId {
+pub(crate) enum Message {
+    // Just an empty frame without even a message id
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
     NotInterested,
-    Have,
-    Bitfield,
-    Request,
-    Piece,
-    Cancel,
+    Have(u32),
+    Bitfield(Bytes),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    // Sliced straight out of the read buffer, so blocks only get copied once
+    // they reach the piece they belong to
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    // The id picks the extension, zero being the extended handshake
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+impl Message {
+    // Size on the wire, length prefix included
+    pub(crate) fn wire_length(&self) -> usize {
+        let payload = match self {
+            Message::KeepAlive => return 4,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 0,
+            Message::Have(_) | Message::Suggest(_) | Message::AllowedFast(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.len(),
+            Message::Request { .. } | Message::Cancel { .. } | Message::Reject { .. } => 12,
+            Message::Piece { block, .. } => 8 + block.len(),
+            Message::Extended { payload, .. } => 1 + payload.len(),
+        };
+
+        5 + payload
+    }
 }
 
-#[repr(C)]
-#[repr(packed)]
 #[derive(Debug, Clone)]
 pub(crate) struct Handshake {
-    pub(crate) length: u8,
-    pub(crate) protocol: [u8; 19],
     pub(crate) reserved: [u8; 8],
     pub(crate) info_hash: [u8; 20],
     pub(crate) peer_id: [u8; 20],
 }
 
 impl Handshake {
+    pub(crate) const LENGTH: usize = 1 + PROTOCOL.len() + 8 + 20 + 20;
+
     pub(crate) fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+        reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+
         Self {
-            length: 19,
-            protocol: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
 
-    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
-        // Help for this came from: https://github.com/jonhoo/codecrafters-bittorrent-rust/blob/master/src/main.rs#L128
-        // Cheers Jon, always teaching me the low-level stuff!
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
+    pub(crate) fn from_bytes(bytes: &[u8; Self::LENGTH]) -> Result<Self> {
+        anyhow::ensure!(
+            bytes[0] as usize == PROTOCOL.len(),
+            "protocol should be 19 bytes long"
+        );
+        anyhow::ensure!(
+            &bytes[1..20] == PROTOCOL,
+            "protocol should be `BitTorrent protocol`"
+        );
+
+        let mut handshake = Self::new([0; 20], [0; 20]);
+        handshake.reserved.copy_from_slice(&bytes[20..28]);
+        handshake.info_hash.copy_from_slice(&bytes[28..48]);
+        handshake.peer_id.copy_from_slice(&bytes[48..68]);
+
+        Ok(handshake)
+    }
 
-        // Safety: Repr C and packed makes this safe
-        let bytes = unsafe { &mut *bytes };
+    pub(crate) fn to_bytes(&self) -> [u8; Self::LENGTH] {
+        let mut bytes = [0; Self::LENGTH];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
 
         bytes
     }
+
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        let has = |(byte, bit): (usize, u8)| self.reserved[byte] & bit != 0;
+
+        Capabilities {
+            extensions: has(EXTENSION_PROTOCOL),
+            fast: has(FAST_EXTENSION),
+            dht: has(DHT),
+        }
+    }
+
+    // Makes sure the peer answered for the torrent we are after and isn't
+    // actually us, which happens when the tracker hands out our own address
+    pub(crate) fn validate(&self, info_hash: &[u8; 20]) -> Result<()> {
+        anyhow::ensure!(
+            &self.info_hash == info_hash,
+            "handshake is for info hash {} instead of {}",
+            hex::encode(self.info_hash),
+            hex::encode(info_hash)
+        );
+        anyhow::ensure!(self.peer_id != *peer_id(), "connected to ourselves");
+
+        Ok(())
+    }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct PeerMessage {
-    pub(crate) id: MessageId,
-    pub(crate) payload: Vec<u8>,
+// What the peer says it supports in the reserved bytes of its handshake
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Capabilities {
+    pub(crate) extensions: bool,
+    pub(crate) fast: bool,
+    pub(crate) dht: bool,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [
+            (self.extensions, "extension protocol"),
+            (self.fast, "fast"),
+            (self.dht, "dht"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect();
+
+        match names.is_empty() {
+            true => write!(f, "none"),
+            false => write!(f, "{}", names.join(", ")),
+        }
+    }
 }
 
-pub(crate) struct Peer {
-    _address: SocketAddrV4,
-    stream: Framed<TcpStream, PeerMessageCodec>,
+pub(crate) struct Peer<S> {
+    address: SocketAddrV4,
+    stream: Framed<Throttled<Transport<S>>, PeerMessageCodec>,
+    bitfield: Bitfield,
+    // Whether they are choking us and whether we want something from them
     choked: bool,
+    interested: bool,
+    // Whether we are choking them, their interest is tracked in the stats
+    choking: bool,
+    requests: HashSet<Block>,
+    // When each request went out, for the latency numbers
+    requested_at: HashMap<Block, Instant>,
+    uploads: VecDeque<Block>,
+    pipeline: Pipeline,
+    stats: Arc<PeerStats>,
+    remote_id: [u8; 20],
+    client: Client,
+    // We speak everything we know of, so this is also what both ends speak.
+    // With the fast extension requests never get dropped without a reject.
+    capabilities: Capabilities,
+    extensions: Extensions,
+    // Pieces they let us have while choked, pieces we let them have while
+    // choked, and pieces they would like us to go for
+    allowed_fast: Bitfield,
+    granted_fast: Bitfield,
+    suggested: Bitfield,
+    // Pieces shown to them while super-seeding, the only ones they get
+    revealed: Bitfield,
+    received_at: Instant,
+    sent_at: Instant,
+    // Last time a block arrived, or when we started waiting for one
+    block_at: Instant,
+    snubbed: bool,
+    // The first message, if it needs processing once the peer is running
+    pending: Option<Message>,
 }
 
-impl Peer {
-    pub(crate) async fn new(addr: SocketAddrV4, info_hash: &[u8; 20]) -> Result<Self> {
-        let mut stream = establish_connection(addr, info_hash)
+impl Peer<Connection> {
+    pub(crate) async fn new(
+        addr: SocketAddrV4,
+        info_hash: &[u8; 20],
+        ours: &Bitfield,
+        dialer: &Dialer,
+        throttle: Throttle,
+    ) -> Result<Self> {
+        let (transport, handshake) = establish_connection(addr, info_hash, dialer)
             .await
             .context("connecting to peer")?;
 
-        let bitfield = stream
-            .next()
+        Self::from_stream(addr, transport, &handshake, ours, throttle).await
+    }
+}
+
+impl<S: Stream> Peer<S> {
+    // Picks up a connection that has already been through the handshake,
+    // `handshake` being the one the peer sent
+    pub(crate) async fn from_stream(
+        addr: SocketAddrV4,
+        transport: Transport<S>,
+        handshake: &Handshake,
+        ours: &Bitfield,
+        throttle: Throttle,
+    ) -> Result<Self> {
+        let piece_count = ours.len();
+        let mut peer = Self {
+            address: addr,
+            stream: Framed::new(
+                Throttled::new(transport, throttle),
+                PeerMessageCodec::new(addr),
+            ),
+            bitfield: Bitfield::new(piece_count),
+            choked: true,
+            interested: false,
+            choking: true,
+            requests: HashSet::new(),
+            requested_at: HashMap::new(),
+            uploads: VecDeque::new(),
+            pipeline: Pipeline::new(DEFAULT_MAX_REQUESTS),
+            stats: Arc::new(PeerStats::new()),
+            remote_id: handshake.peer_id,
+            client: Client::identify(&handshake.peer_id),
+            capabilities: handshake.capabilities(),
+            extensions: Extensions::new(),
+            allowed_fast: Bitfield::new(piece_count),
+            granted_fast: allowed_fast_set(
+                addr,
+                &handshake.info_hash,
+                piece_count,
+                ALLOWED_FAST_PIECES,
+            ),
+            suggested: Bitfield::new(piece_count),
+            revealed: Bitfield::new(piece_count),
+            received_at: Instant::now(),
+            sent_at: Instant::now(),
+            block_at: Instant::now(),
+            snubbed: false,
+            pending: None,
+        };
+
+        // Ours goes first, some peers won't say anything until they know
+        // what we have. The fast extension makes saying something mandatory.
+        if peer.capabilities.fast && ours.has_all() {
+            peer.send(Message::HaveAll)
+                .await
+                .context("sending have all")?;
+        } else if ours.has_any() {
+            peer.send(Message::Bitfield(Bytes::copy_from_slice(ours.as_bytes())))
+                .await
+                .context("sending bitfield")?;
+        } else if peer.capabilities.fast {
+            peer.send(Message::HaveNone)
+                .await
+                .context("sending have none")?;
+        }
+
+        // The bitfield is only allowed as the first message and peers
+        // without any pieces are free to skip it entirely
+        let first = time::timeout(READ_TIMEOUT, peer.next_message())
             .await
-            .expect("always start with a bitfield")
-            .context("bitfield message was invalid")?;
+            .context("peer never sent its first message")??;
+        match first {
+            Message::Bitfield(payload) => {
+                peer.bitfield = Bitfield::from_payload(&payload, piece_count)
+                    .context("peer sent an invalid bitfield")?;
+            }
+            Message::HaveAll if peer.capabilities.fast => {
+                peer.bitfield = Bitfield::full(piece_count)
+            }
+            Message::HaveNone if peer.capabilities.fast => {}
+            first => {
+                if peer.handle(&first)? {
+                    peer.pending = Some(first);
+                }
+            }
+        }
 
-        anyhow::ensure!(bitfield.id == MessageId::Bitfield);
+        Ok(peer)
+    }
 
-        Ok(Self {
-            _address: addr,
-            stream,
-            choked: true,
-        })
+    pub(crate) fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    pub(crate) fn stats(&self) -> Arc<PeerStats> {
+        self.stats.clone()
+    }
+
+    // The software on the other end, going by its peer id
+    pub(crate) fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub(crate) fn has_piece(&self, piece_id: usize) -> bool {
+        self.bitfield.has(piece_id)
+    }
+
+    pub(crate) fn set_max_requests(&mut self, max: usize) {
+        self.pipeline = Pipeline::new(max);
+    }
+
+    // Trades pieces with the peer, downloading whatever the swarm still needs
+    // and serving whatever it has, until the connection goes away
+    pub(crate) async fn run(&mut self, swarm: &Swarm) -> Result<()> {
+        let (tx, mut commands) = mpsc::unbounded_channel();
+        swarm.peer_connected(PeerHandle::new(
+            self.address,
+            self.client.clone(),
+            self.stats.clone(),
+            tx,
+        ));
+        swarm.picker().peer_connected(&self.bitfield);
+
+        let result = self.exchange(swarm, &mut commands).await;
+
+        swarm.peer_disconnected(&self.address);
+        {
+            let mut picker = swarm.picker();
+            for block in self.requests.drain() {
+                picker.release(&block);
+            }
+            picker.peer_disconnected(&self.bitfield);
+        }
+        swarm.changed();
+
+        result.with_context(|| format!("peer {} ({})", self.address, self.remote_id.escape_ascii()))
+    }
+
+    async fn send_extended_handshake(&mut self, swarm: &Swarm) -> Result<()> {
+        if !self.capabilities.extensions {
+            return Ok(());
+        }
+
+        let handshake = ExtendedHandshake {
+            v: Some(ByteBuf::from(CLIENT.as_bytes())),
+            p: swarm.port(),
+            reqq: Some(MAX_QUEUED_UPLOADS as i64),
+            yourip: Some(ByteBuf::from(self.address.ip().octets())),
+            ..Default::default()
+        };
+        let message = self.extensions.handshake(handshake)?;
+
+        self.send(message)
+            .await
+            .context("sending extended handshake")
+    }
+
+    // Lets the peer get going on a few pieces before we unchoke it
+    async fn send_allowed_fast(&mut self, swarm: &Swarm) -> Result<()> {
+        // That would give away pieces we are pretending not to have
+        if !self.capabilities.fast || swarm.is_super_seeding() {
+            return Ok(());
+        }
+
+        for piece in 0..self.granted_fast.len() {
+            if self.granted_fast.has(piece) && swarm.has_piece(piece) {
+                self.send(Message::AllowedFast(piece as u32))
+                    .await
+                    .context("sending allowed fast")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Shows them the next piece while super-seeding
+    async fn reveal(&mut self, swarm: &Swarm) -> Result<()> {
+        let Some(piece) = swarm.offer(self.address, &self.bitfield) else {
+            return Ok(());
+        };
+
+        self.revealed.set(piece)?;
+        self.send(Message::Have(piece as u32))
+            .await
+            .with_context(|| format!("revealing piece {piece}"))
     }
 
-    pub(crate) async fn download_piece(
+    async fn exchange(
         &mut self,
-        piece_id: usize,
-        piece_length: usize,
-    ) -> Result<Vec<u8>> {
-        if self.choked {
-            self.interested()
+        swarm: &Swarm,
+        commands: &mut mpsc::UnboundedReceiver<PeerCommand>,
+    ) -> Result<()> {
+        let mut changes = swarm.subscribe();
+        let mut cancels = swarm.cancels();
+        let mut haves = swarm.haves();
+
+        self.send_extended_handshake(swarm).await?;
+        self.send_allowed_fast(swarm).await?;
+        if swarm.is_super_seeding() {
+            self.reveal(swarm).await?;
+        }
+
+        // A have went into the bitfield the picker was told about already
+        match self.pending.take() {
+            Some(Message::Have(piece)) => swarm.piece_seen(self.address, piece as usize),
+            Some(message) => self.process(swarm, message).await?,
+            None => {}
+        }
+
+        self.pipeline.restart();
+        self.block_at = Instant::now();
+        loop {
+            changes.borrow_and_update();
+            self.update_interest(swarm).await?;
+
+            self.request_blocks(swarm).await?;
+
+            tokio::select! {
+                _ = time::sleep_until(self.next_deadline().into()) => self.check_timeouts(swarm).await?,
+                message = self.recv() => {
+                    let message = message.context("invalid peer response")?;
+                    self.process(swarm, message).await?;
+                }
+                Some(command) = commands.recv() => self.command(swarm, command).await?,
+                // Blocks released by other peers may be up for grabs now
+                _ = changes.changed(), if self.requests.is_empty() => {}
+                cancelled = cancels.recv() => {
+                    if let Ok(block) = cancelled {
+                        if self.requests.remove(&block) {
+                            self.send(Message::Cancel {
+                                index: block.piece,
+                                begin: block.begin,
+                                length: block.length,
+                            })
+                            .await
+                            .context("cancelling request")?;
+                        }
+                    }
+                }
+                have = haves.recv() => {
+                    if let Ok(piece) = have {
+                        if !self.bitfield.has(piece) {
+                            self.send(Message::Have(piece as u32))
+                                .await
+                                .with_context(|| format!("announcing piece {piece}"))?;
+                        }
+                    }
+                }
+                // Uploads go out one at a time so cancels get a chance to catch them
+                _ = std::future::ready(()), if !self.uploads.is_empty() => {
+                    self.upload(swarm).await?;
+                }
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let mut deadline =
+            (self.received_at + READ_TIMEOUT).min(self.sent_at + KEEP_ALIVE_INTERVAL);
+        if !self.requests.is_empty() && !self.snubbed {
+            deadline = deadline.min(self.block_at + REQUEST_TIMEOUT);
+        }
+
+        deadline
+    }
+
+    async fn check_timeouts(&mut self, swarm: &Swarm) -> Result<()> {
+        let now = Instant::now();
+        anyhow::ensure!(
+            now < self.received_at + READ_TIMEOUT,
+            "peer sent nothing for {} seconds",
+            READ_TIMEOUT.as_secs()
+        );
+
+        if !self.requests.is_empty() && !self.snubbed && now >= self.block_at + REQUEST_TIMEOUT {
+            // Someone else can have a go at whatever they are sitting on. Any
+            // of it that still turns up late is taken all the same.
+            self.snubbed = true;
+            self.stats.set_snubbed(true);
+            self.release_requests(swarm);
+        }
+
+        if now >= self.sent_at + KEEP_ALIVE_INTERVAL {
+            self.send(Message::KeepAlive)
                 .await
-                .context("sending interested message")?;
+                .context("sending keep-alive")?;
         }
 
-        let blocks = (piece_length + (BLOCK_SIZE - 1)) / BLOCK_SIZE;
+        Ok(())
+    }
 
-        let mut content = Vec::with_capacity(piece_length);
-        for block in 0..blocks {
-            let size = if block == blocks - 1 {
-                let remainder = piece_length % BLOCK_SIZE;
-                if remainder == 0 {
-                    BLOCK_SIZE
-                } else {
-                    remainder
+    fn release_requests(&mut self, swarm: &Swarm) {
+        let mut picker = swarm.picker();
+        for block in self.requests.drain() {
+            picker.release(&block);
+        }
+        drop(picker);
+        swarm.changed();
+    }
+
+    async fn command(&mut self, swarm: &Swarm, command: PeerCommand) -> Result<()> {
+        match command {
+            PeerCommand::Choke if !self.choking => {
+                self.send(Message::Choke).await.context("choking peer")?;
+
+                // Choking throws away whatever they asked for, apart from
+                // pieces they are allowed to have anyway. With the fast
+                // extension they get told about it.
+                let (kept, rejected) =
+                    std::mem::take(&mut self.uploads)
+                        .into_iter()
+                        .partition(|block| {
+                            self.capabilities.fast && self.granted_fast.has(block.piece as usize)
+                        });
+                self.uploads = kept;
+                for block in rejected {
+                    self.reject(block).await?;
                 }
+                self.choking = true;
+            }
+            PeerCommand::Unchoke if self.choking => {
+                self.send(Message::Unchoke)
+                    .await
+                    .context("unchoking peer")?;
+                self.choking = false;
+            }
+            PeerCommand::Ban => anyhow::bail!("banned for sending bad data"),
+            PeerCommand::Disconnect => anyhow::bail!("told to disconnect"),
+            PeerCommand::Reveal => self.reveal(swarm).await?,
+            _ => {}
+        }
+
+        self.stats.set_choked(self.choking);
+
+        Ok(())
+    }
+
+    async fn update_interest(&mut self, swarm: &Swarm) -> Result<()> {
+        let interesting = swarm.picker().is_interesting(&self.bitfield);
+        if interesting != self.interested {
+            let message = if interesting {
+                Message::Interested
             } else {
-                BLOCK_SIZE
+                Message::NotInterested
             };
 
-            let offset = block * BLOCK_SIZE;
-            let mut request = PieceRequest::new(piece_id as u32, offset as u32, size as u32);
+            self.send(message).await.context("updating interest")?;
+            self.interested = interesting;
+        }
 
-            let request = PeerMessage {
-                id: MessageId::Request,
-                payload: request.as_bytes_mut().to_vec(),
-            };
+        Ok(())
+    }
 
-            self.stream
-                .send(request)
-                .await
-                .with_context(|| format!("sending piece request for block {block}"))?;
+    async fn request_blocks(&mut self, swarm: &Swarm) -> Result<()> {
+        // Snubbing peers only get one request at a time until they deliver
+        let depth = if self.snubbed {
+            1
+        } else {
+            self.pipeline.depth()
+        };
+        // The peer may have told us how many requests it is willing to queue
+        let depth = match self.extensions.remote().and_then(|remote| remote.reqq) {
+            Some(reqq) => depth.min(reqq.max(1) as usize),
+            None => depth,
+        };
+        let wanted = depth.saturating_sub(self.requests.len());
 
-            let piece = self
-                .stream
-                .next()
-                .await
-                .expect("always sends a piece back")
-                .context("invalid peer response")?;
+        // While choked only the allowed fast pieces are any use, otherwise
+        // suggestions get first go
+        let preferred = if self.choked {
+            self.allowed_fast.intersection(&self.bitfield)
+        } else {
+            self.suggested.intersection(&self.bitfield)
+        };
+        if self.choked && !preferred.has_any() {
+            return Ok(());
+        }
+
+        let (blocks, endgame_started) = {
+            let mut picker = swarm.picker();
+            let endgame = picker.in_endgame();
+            let mut blocks = picker.pick(&preferred, self.address, &self.requests, wanted);
+            if !self.choked && blocks.len() < wanted {
+                let requested: HashSet<Block> =
+                    self.requests.iter().chain(&blocks).copied().collect();
+                blocks.extend(picker.pick(
+                    &self.bitfield,
+                    self.address,
+                    &requested,
+                    wanted - blocks.len(),
+                ));
+            }
+            (blocks, !endgame && picker.in_endgame())
+        };
+
+        // Idle peers can start doubling up on requests now
+        if endgame_started {
+            swarm.changed();
+        }
 
-            let piece = Piece::from(piece.payload);
-            anyhow::ensure!(piece.index as usize == piece_id);
-            anyhow::ensure!(piece.begin as usize == (block * BLOCK_SIZE));
+        if self.requests.is_empty() && !blocks.is_empty() {
+            self.block_at = Instant::now();
+        }
 
-            content.extend(piece.block);
+        let requests = &self.requests;
+        self.requested_at
+            .retain(|block, _| requests.contains(block));
+        for block in blocks {
+            self.send(Message::Request {
+                index: block.piece,
+                begin: block.begin,
+                length: block.length,
+            })
+            .await
+            .with_context(|| {
+                format!(
+                    "requesting block at {} of piece {}",
+                    block.begin, block.piece
+                )
+            })?;
+            self.requests.insert(block);
+            self.requested_at.insert(block, Instant::now());
         }
 
-        Ok(content)
+        Ok(())
     }
 
-    async fn interested(&mut self) -> Result<()> {
-        let interested = PeerMessage {
-            id: MessageId::Interested,
-            payload: Vec::new(),
+    async fn process(&mut self, swarm: &Swarm, message: Message) -> Result<()> {
+        match message {
+            // Anything in flight is dropped by the peer, let someone else have
+            // it. The fast extension rejects them one by one instead.
+            Message::Choke if !self.capabilities.fast => self.release_requests(swarm),
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                if self.requests.remove(&block) {
+                    self.stats.block_rejected();
+                    swarm.picker().release(&block);
+                    swarm.changed();
+                }
+            }
+            Message::Have(piece) => {
+                swarm.picker().peer_has(piece as usize);
+                swarm.piece_seen(self.address, piece as usize);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block: data,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length: data.len() as u32,
+                };
+
+                // Blocks can still arrive after a choke released them, the
+                // picker takes them as long as nobody beat this peer to it
+                let ours = self.requests.remove(&block);
+                self.pipeline.block_received(data.len());
+                self.stats.add_downloaded(data.len());
+                let latency = self.requested_at.remove(&block).map(|at| at.elapsed());
+                self.stats.block_received(latency);
+                self.block_at = Instant::now();
+                if self.snubbed {
+                    self.snubbed = false;
+                    self.stats.set_snubbed(false);
+                }
+
+                let (outcome, contested) = {
+                    let mut picker = swarm.picker();
+                    let others = picker.requesters(&block).saturating_sub(usize::from(ours));
+                    (
+                        picker.block_received(&block, &data, self.address),
+                        others > 0,
+                    )
+                };
+
+                if contested {
+                    swarm.cancel(block);
+                }
+
+                match outcome {
+                    BlockOutcome::Completed(data, culprits) => {
+                        swarm.strike(&culprits);
+                        swarm.piece_completed(block.piece as usize, data)?
+                    }
+                    BlockOutcome::Failed(culprits) => {
+                        swarm.strike(&culprits);
+                        swarm.changed();
+                    }
+                    BlockOutcome::Stored | BlockOutcome::Ignored => {}
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                self.validate_request(swarm, &block)?;
+
+                // Requests while choked are turned down unless the piece is
+                // allowed fast, as are ones for pieces we haven't got yet
+                let allowed = !self.choking
+                    || self.capabilities.fast && self.granted_fast.has(block.piece as usize);
+                if self.uploads.contains(&block) {
+                    return Ok(());
+                }
+
+                // While super-seeding only what they were shown, like they
+                // would expect
+                let available = swarm.has_piece(block.piece as usize)
+                    && (!swarm.is_super_seeding() || self.revealed.has(block.piece as usize));
+                if allowed && available && self.uploads.len() < MAX_QUEUED_UPLOADS {
+                    self.uploads.push_back(block);
+                } else {
+                    self.reject(block).await?;
+                }
+            }
+            Message::Interested => swarm.peer_interested(),
+            Message::Extended { id, payload } => {
+                let replies = self
+                    .extensions
+                    .message(swarm, &mut self.bitfield, id, &payload)?;
+                for reply in replies {
+                    self.send(reply)
+                        .await
+                        .context("replying to extended message")?;
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                let queued = self.uploads.len();
+                self.uploads.retain(|upload| *upload != block);
+                if self.uploads.len() < queued {
+                    self.reject(block).await?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn validate_request(&self, swarm: &Swarm, block: &Block) -> Result<()> {
+        let torrent = swarm.torrent();
+        let piece = block.piece as usize;
+        anyhow::ensure!(
+            piece < torrent.info.pieces.0.len(),
+            "peer requested piece {piece} which does not exist"
+        );
+        anyhow::ensure!(
+            block.length > 0 && block.length as usize <= MAX_REQUEST_LENGTH,
+            "peer requested {} bytes at once",
+            block.length
+        );
+        anyhow::ensure!(
+            // Widened first, the peer picks both and they could wrap around
+            block.begin as u64 + block.length as u64 <= torrent.piece_length(piece) as u64,
+            "peer requested past the end of piece {piece}"
+        );
+
+        Ok(())
+    }
+
+    async fn upload(&mut self, swarm: &Swarm) -> Result<()> {
+        let Some(block) = self.uploads.pop_front() else {
+            return Ok(());
         };
 
-        self.stream
-            .send(interested)
-            .await
-            .context("sending interested message")?;
+        let data = swarm
+            .read_block(&block)
+            .context("reading requested block")?;
+        self.stats.add_uploaded(data.len());
 
-        let unchoke = self
-            .stream
-            .next()
+        let message = Message::Piece {
+            index: block.piece,
+            begin: block.begin,
+            block: Bytes::from(data),
+        };
+        self.send(message).await.with_context(|| {
+            format!(
+                "uploading block at {} of piece {}",
+                block.begin, block.piece
+            )
+        })
+    }
+
+    // Without the fast extension requests are simply dropped
+    async fn reject(&mut self, block: Block) -> Result<()> {
+        if self.capabilities.fast {
+            self.send(Message::Reject {
+                index: block.piece,
+                begin: block.begin,
+                length: block.length,
+            })
             .await
-            .expect("always sends an unchoke")
-            .context("invalid peer message")?;
+            .with_context(|| {
+                format!(
+                    "rejecting block at {} of piece {}",
+                    block.begin, block.piece
+                )
+            })?;
+        }
 
-        anyhow::ensure!(unchoke.id == MessageId::Unchoke);
-        self.choked = false;
+        Ok(())
+    }
+
+    async fn send(&mut self, message: Message) -> Result<()> {
+        self.stats.add_sent(message.wire_length());
+        self.stream
+            .send(message)
+            .await
+            .context("sending peer message")?;
+        self.sent_at = Instant::now();
 
         Ok(())
     }
+
+    // Keep-alives only matter for knowing the peer is still there
+    async fn next_message(&mut self) -> Result<Message> {
+        loop {
+            let message = self
+                .stream
+                .next()
+                .await
+                .context("peer closed the connection")?
+                .context("invalid peer message")?;
+            self.received_at = Instant::now();
+            self.stats.add_received(message.wire_length());
+
+            if message != Message::KeepAlive {
+                return Ok(message);
+            }
+        }
+    }
+
+    // Receives the next message, keeping track of any state it changes
+    async fn recv(&mut self) -> Result<Message> {
+        loop {
+            let message = self.next_message().await?;
+            if self.handle(&message)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    // False for messages that tell us nothing new, like a have for a piece
+    // we already knew the peer had. Counting those again would throw off
+    // availability.
+    fn handle(&mut self, message: &Message) -> Result<bool> {
+        match message {
+            // BEP 6 says to hang up on these unless both ends said they
+            // speak the fast extension
+            Message::Suggest(_)
+            | Message::HaveAll
+            | Message::HaveNone
+            | Message::Reject { .. }
+            | Message::AllowedFast(_)
+                if !self.capabilities.fast =>
+            {
+                anyhow::bail!("peer sent {message:?} without the fast extension")
+            }
+            Message::Choke => self.choked = true,
+            Message::Unchoke => self.choked = false,
+            Message::Interested => self.stats.set_interested(true),
+            Message::NotInterested => self.stats.set_interested(false),
+            Message::Have(piece) => {
+                return self
+                    .bitfield
+                    .set(*piece as usize)
+                    .context("peer sent an invalid have message")
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                anyhow::bail!(
+                    "bitfield, have all and have none are only valid as the first message"
+                )
+            }
+            Message::AllowedFast(piece) => {
+                self.allowed_fast
+                    .set(*piece as usize)
+                    .context("peer allowed an invalid piece")?;
+            }
+            Message::Suggest(piece) => {
+                self.suggested
+                    .set(*piece as usize)
+                    .context("peer suggested an invalid piece")?;
+            }
+            Message::Extended { id, payload } => {
+                // The handshake only tells us what else the peer supports, the
+                // connection is still good without it
+                if let Err(e) = self.extensions.remote_handshake(*id, payload) {
+                    eprintln!("peer {}: {e:#}, carrying on without it", self.address);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(true)
+    }
+}
+
+// What we call ourselves in the extended handshake
+const CLIENT: &str = concat!("bittorrent-starter-rust ", env!("CARGO_PKG_VERSION"));
+
+// Azureus style, client code followed by version and then random digits
+pub(crate) fn peer_id() -> &'static [u8; 20] {
+    static PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    PEER_ID.get_or_init(|| {
+        let mut id = *b"-CC0100-000000000000";
+        let mut rng = Rng::new();
+        for digit in &mut id[8..] {
+            *digit = b'0' + rng.below(10) as u8;
+        }
+        id
+    })
 }
 
-async fn establish_connection(
+pub(crate) async fn establish_connection(
     address: SocketAddrV4,
     info_hash: &[u8; 20],
-) -> Result<Framed<TcpStream, PeerMessageCodec>> {
-    let mut peer = TcpStream::connect(address)
+    dialer: &Dialer,
+) -> Result<(Transport<Connection>, Handshake)> {
+    let mut peer = dialer.connect(address, info_hash).await?;
+
+    let trace = Trace::new(address);
+    let handshake = Handshake::new(*info_hash, *peer_id());
+    trace.handshake(Direction::Sent, &handshake);
+    peer.write_all(&handshake.to_bytes())
         .await
-        .context("connecting to peer")?;
+        .context("sending handshake")?;
+    peer.flush().await.context("sending handshake")?;
 
-    let mut handshake = Handshake::new(*info_hash, *b"00112233445566778899");
+    let mut bytes = [0; Handshake::LENGTH];
+    peer.read_exact(&mut bytes)
+        .await
+        .context("receiving handshake")?;
+    let handshake = Handshake::from_bytes(&bytes)?;
+    trace.handshake(Direction::Received, &handshake);
+    handshake.validate(info_hash)?;
 
-    {
-        let handshake_bytes = handshake.as_bytes_mut();
+    Ok((peer, handshake))
+}
 
-        peer.write_all(handshake_bytes)
-            .await
-            .context("sending handshake")?;
-        peer.read_exact(handshake_bytes)
-            .await
-            .context("receiving handshake")?;
-    }
+// The canonical allowed fast set from BEP 6, so the peer can work out which
+// pieces it gets without us having to tell it
+fn allowed_fast_set(
+    address: SocketAddrV4,
+    info_hash: &[u8; 20],
+    pieces: usize,
+    wanted: usize,
+) -> Bitfield {
+    let mut allowed = Bitfield::new(pieces);
+    let wanted = wanted.min(pieces);
 
-    anyhow::ensure!(handshake.length == 19, "protocol should be 19 bytes long");
-    anyhow::ensure!(
-        &handshake.protocol == b"BitTorrent protocol",
-        "protocol should be `BitTorrent protocol`"
-    );
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(*address.ip()) & 0xffffff00).to_be_bytes());
+    x.extend_from_slice(info_hash);
 
-    Ok(Framed::new(peer, PeerMessageCodec))
+    let mut count = 0;
+    while count < wanted {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if count == wanted {
+                break;
+            }
+
+            let y = u32::from_be_bytes(chunk.try_into().expect("4 bytes"));
+            let piece = y as usize % pieces;
+            if !allowed.has(piece) {
+                allowed.set(piece).expect("piece is in range");
+                count += 1;
+            }
+        }
+    }
+
+    allowed
 }
 
-#[repr(C)]
-#[repr(packed)]
-struct PieceRequest {
-    index: [u8; 4],
-    begin: [u8; 4],
-    length: [u8; 4],
+// Sizes the request queue from the observed download rate, so fast peers get
+// enough requests to stay busy and slow ones aren't buried under a huge backlog
+struct Pipeline {
+    max: usize,
+    depth: usize,
+    rate: f64,
+    last_block: Instant,
 }
 
-impl PieceRequest {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+impl Pipeline {
+    fn new(max: usize) -> Self {
+        let max = max.max(1);
         Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
+            max,
+            depth: MIN_REQUESTS.min(max),
+            rate: 0.0,
+            last_block: Instant::now(),
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        // Safety: Self has repr C and packed
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+    fn depth(&self) -> usize {
+        self.depth
+    }
 
-        bytes
+    // Time spent between pieces shouldn't count against the rate
+    fn restart(&mut self) {
+        self.last_block = Instant::now();
     }
-}
 
-#[repr(C)]
-pub(crate) struct Piece {
-    index: u32,
-    begin: u32,
-    block: Vec<u8>,
-}
+    fn block_received(&mut self, length: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_block).as_secs_f64().max(1e-3);
+        self.last_block = now;
 
-impl From<Vec<u8>> for Piece {
-    fn from(value: Vec<u8>) -> Self {
-        let index = &value[..4];
-        let begin = &value[4..8];
-        let block = &value[8..];
+        let sample = length as f64 / elapsed;
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            0.8 * self.rate + 0.2 * sample
+        };
 
-        Self {
-            index: u32::from_be_bytes(index.try_into().expect("should have 4 bytes")),
-            begin: u32::from_be_bytes(begin.try_into().expect("should have 4 bytes")),
-            block: block.to_vec(),
-        }
+        let wanted = (self.rate * REQUEST_QUEUE_SECS / BLOCK_SIZE as f64).ceil() as usize;
+        self.depth = wanted.clamp(MIN_REQUESTS.min(self.max), self.max);
     }
 }
 
 // Again, idea for using codec comes from Jon Gjengset implementation
 // but going to give it a go myself
 // Good resource here: https://docs.rs/tokio-util/latest/tokio_util/codec/index.html
-pub(crate) struct PeerMessageCodec;
+pub(crate) struct PeerMessageCodec {
+    trace: Trace,
+}
+
+impl PeerMessageCodec {
+    pub(crate) fn new(peer: SocketAddrV4) -> Self {
+        Self {
+            trace: Trace::new(peer),
+        }
+    }
+}
 
 impl Decoder for PeerMessageCodec {
-    type Item = PeerMessage;
-    type Error = std::io::Error;
+    type Item = Message;
+    type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Need the length parameter
-        if src.len() < 4 {
-            return Ok(None);
+        let decoded = decode_frame(src);
+        match &decoded {
+            Ok(Some(message)) => self.trace.message(Direction::Received, message),
+            Ok(None) => {}
+            Err(e) => self.trace.error(e),
         }
 
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+        decoded
+    }
+}
+
+fn decode_frame(src: &mut BytesMut) -> io::Result<Option<Message>> {
+    // Need the length parameter
+    if src.len() < 4 {
+        return Ok(None);
+    }
+
+    let mut length_bytes = [0u8; 4];
+    length_bytes.copy_from_slice(&src[..4]);
+    let length = u32::from_be_bytes(length_bytes) as usize;
+
+    if length == 0 {
+        // heartbeat apparently
+        src.advance(4);
+        return Ok(Some(Message::KeepAlive));
+    }
+
+    if length > MAX {
+        return Err(invalid(format!("Frame of length {length} is too large")));
+    }
+
+    if src.len() < 4 + length {
+        // Full data has not arrived yet
+        //
+        // Reserve more space in the buffer
+        src.reserve(4 + length - src.len());
+        return Ok(None);
+    }
+
+    // Payloads keep pointing into the buffer the frame was read into
+    let mut frame = src.split_to(4 + length).freeze();
+    frame.advance(4);
+    let id = frame.get_u8();
+    decode_message(id, frame).map(Some)
+}
 
-        if length == 0 {
-            // heartbeat apparently
-            src.advance(4);
-            return self.decode(src);
+fn decode_message(id: u8, payload: Bytes) -> io::Result<Message> {
+    // Everything but the bitfield, piece and extended messages has a fixed size
+    let expected = match id {
+        0..=3 | 14 | 15 => Some(0),
+        4 | 13 | 17 => Some(4),
+        6 | 8 | 16 => Some(12),
+        _ => None,
+    };
+    if let Some(expected) = expected {
+        if payload.len() != expected {
+            return Err(invalid(format!(
+                "message {id} should carry {expected} bytes, got {}",
+                payload.len()
+            )));
         }
+    }
+
+    let u32_at = |at: usize| {
+        payload
+            .get(at..at + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+            .ok_or_else(|| invalid(format!("message {id} is too short")))
+    };
 
-        // Need to read the id, not enough bytes
-        if src.len() < 5 {
-            return Ok(None);
+    Ok(match id {
+        0 => Message::Choke,
+        1 => Message::Unchoke,
+        2 => Message::Interested,
+        3 => Message::NotInterested,
+        4 => Message::Have(u32_at(0)?),
+        5 => Message::Bitfield(payload),
+        6 => Message::Request {
+            index: u32_at(0)?,
+            begin: u32_at(4)?,
+            length: u32_at(8)?,
+        },
+        7 => Message::Piece {
+            index: u32_at(0)?,
+            begin: u32_at(4)?,
+            block: payload.slice(8..),
+        },
+        8 => Message::Cancel {
+            index: u32_at(0)?,
+            begin: u32_at(4)?,
+            length: u32_at(8)?,
+        },
+        13 => Message::Suggest(u32_at(0)?),
+        14 => Message::HaveAll,
+        15 => Message::HaveNone,
+        16 => Message::Reject {
+            index: u32_at(0)?,
+            begin: u32_at(4)?,
+            length: u32_at(8)?,
+        },
+        17 => Message::AllowedFast(u32_at(0)?),
+        20 => {
+            let id = *payload
+                .first()
+                .ok_or_else(|| invalid("extended message is missing its id".to_string()))?;
+            Message::Extended {
+                id,
+                payload: payload.slice(1..),
+            }
         }
+        _ => return Err(invalid(format!("invalid message id {id} received"))),
+    })
+}
 
-        if length > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {length} is too large"),
-            ));
-        }
-
-        if src.len() < 4 + length {
-            // Full data has not arrived yet
-            //
-            // Reserve more space in the buffer
-            src.reserve(4 + length - src.len());
-            return Ok(None);
-        }
-
-        let message_id = match src[4] {
-            0 => MessageId::Choke,
-            1 => MessageId::Unchoke,
-            2 => MessageId::Interested,
-            3 => MessageId::NotInterested,
-            4 => MessageId::Have,
-            5 => MessageId::Bitfield,
-            6 => MessageId::Request,
-            7 => MessageId::Piece,
-            8 => MessageId::Cancel,
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("invalid message id {} received", src[4]),
-                ))
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Encoder<Message> for PeerMessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.trace.message(Direction::Sent, &message);
+
+        // The length goes in front once we know what it is
+        let start = dst.len();
+        dst.put_u32(0);
+
+        match message {
+            Message::KeepAlive => return Ok(()),
+            Message::Choke => dst.put_u8(0),
+            Message::Unchoke => dst.put_u8(1),
+            Message::Interested => dst.put_u8(2),
+            Message::NotInterested => dst.put_u8(3),
+            Message::Have(piece) => {
+                dst.put_u8(4);
+                dst.put_u32(piece);
+            }
+            Message::Bitfield(bitfield) => {
+                dst.put_u8(5);
+                dst.extend_from_slice(&bitfield);
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => put_block(dst, 6, index, begin, length),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.reserve(9 + block.len());
+                dst.put_u8(7);
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.extend_from_slice(&block);
             }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => put_block(dst, 8, index, begin, length),
+            Message::Suggest(piece) => {
+                dst.put_u8(13);
+                dst.put_u32(piece);
+            }
+            Message::HaveAll => dst.put_u8(14),
+            Message::HaveNone => dst.put_u8(15),
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => put_block(dst, 16, index, begin, length),
+            Message::AllowedFast(piece) => {
+                dst.put_u8(17);
+                dst.put_u32(piece);
+            }
+            Message::Extended { id, payload } => {
+                dst.put_u8(20);
+                dst.put_u8(id);
+                dst.extend_from_slice(&payload);
+            }
+        }
+
+        let length = dst.len() - start - 4;
+        if length > MAX {
+            dst.truncate(start);
+            return Err(invalid(format!("Frame length {length} is too large")));
+        }
+        dst[start..start + 4].copy_from_slice(&(length as u32).to_be_bytes());
+
+        Ok(())
+    }
+}
+
+fn put_block(dst: &mut BytesMut, id: u8, index: u32, begin: u32, length: u32) {
+    dst.put_u8(id);
+    dst.put_u32(index);
+    dst.put_u32(begin);
+    dst.put_u32(length);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn codec() -> PeerMessageCodec {
+        PeerMessageCodec::new(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881))
+    }
+
+    // A length prefixed frame with message `id` and `payload`
+    fn frame(id: u8, payload: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        frame.put_u32(1 + payload.len() as u32);
+        frame.put_u8(id);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn decode(mut frame: BytesMut) -> io::Result<Option<Message>> {
+        codec().decode(&mut frame)
+    }
+
+    #[test]
+    fn short_payloads() {
+        for (id, length) in [(4, 3), (6, 11), (7, 7), (7, 0), (16, 8), (17, 2)] {
+            let error = decode(frame(id, &vec![0; length])).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "message {id}");
+        }
+        // Fixed size messages can't be any longer either
+        assert!(decode(frame(4, &[0; 5])).is_err());
+        assert!(decode(frame(1, &[0])).is_err());
+    }
+
+    #[test]
+    fn unknown_id() {
+        for id in [9, 10, 12, 18, 21, 255] {
+            let error = decode(frame(id, &[])).unwrap_err();
+            assert!(error.to_string().contains("invalid message id"), "{error}");
+        }
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut frame = BytesMut::new();
+        frame.put_u32(MAX as u32 + 1);
+        frame.put_u8(7);
+        let error = decode(frame).unwrap_err();
+        assert!(error.to_string().contains("too large"), "{error}");
+
+        let mut dst = BytesMut::new();
+        let message = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: Bytes::from(vec![0; MAX_REQUEST_LENGTH + 1]),
         };
+        assert!(codec().encode(message, &mut dst).is_err());
+        assert!(dst.is_empty());
+    }
 
-        let payload = if src.len() > 5 {
-            src[5..4 + length].to_vec()
-        } else {
-            Vec::new()
+    #[test]
+    fn extended_without_id() {
+        let error = decode(frame(20, &[])).unwrap_err();
+        assert!(error.to_string().contains("missing its id"), "{error}");
+    }
+
+    #[test]
+    fn incomplete_frames() {
+        let whole = frame(6, &[0; 12]);
+        for length in 0..whole.len() {
+            let mut partial = BytesMut::from(&whole[..length]);
+            assert_eq!(codec().decode(&mut partial).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn bitfield_spare_bits() {
+        // 10 pieces leave 6 spare bits in the second byte
+        let Some(Message::Bitfield(payload)) = decode(frame(5, &[0xff, 0xc0])).unwrap() else {
+            panic!("expected a bitfield");
         };
+        assert!(Bitfield::from_payload(&payload, 10).is_ok());
 
-        src.advance(4 + length);
+        let Some(Message::Bitfield(payload)) = decode(frame(5, &[0xff, 0xc1])).unwrap() else {
+            panic!("expected a bitfield");
+        };
+        assert!(Bitfield::from_payload(&payload, 10).is_err());
+    }
 
-        Ok(Some(PeerMessage {
-            id: message_id,
-            payload,
-        }))
+    #[test]
+    fn round_trip() {
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0xdead_beef),
+            Message::Bitfield(Bytes::from_static(&[0xa5, 0x80])),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 2,
+                begin: 32768,
+                block: Bytes::from_static(b"some block data"),
+            },
+            Message::Cancel {
+                index: 3,
+                begin: 0,
+                length: 8,
+            },
+            Message::Suggest(4),
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::Reject {
+                index: 5,
+                begin: 16,
+                length: 32,
+            },
+            Message::AllowedFast(6),
+            Message::Extended {
+                id: 0,
+                payload: Bytes::from_static(b"d1:md11:ut_donthavei1eee"),
+            },
+        ];
+
+        // All in one buffer, the way they'd come off the socket
+        let mut codec = codec();
+        let mut buffer = BytesMut::new();
+        for message in &messages {
+            let start = buffer.len();
+            codec.encode(message.clone(), &mut buffer).unwrap();
+            assert_eq!(buffer.len() - start, message.wire_length(), "{message:?}");
+        }
+        for message in &messages {
+            assert_eq!(codec.decode(&mut buffer).unwrap().as_ref(), Some(message));
+        }
+        assert!(buffer.is_empty());
     }
-}
 
-impl Encoder<PeerMessage> for PeerMessageCodec {
-    type Error = std::io::Error;
+    #[test]
+    fn allowed_fast_reference() {
+        // The example from BEP 6
+        let address = SocketAddrV4::new(Ipv4Addr::new(80, 4, 4, 200), 6881);
+        let info_hash = [0xaa; 20];
+        let pieces = |allowed: Bitfield| -> Vec<usize> {
+            (0..allowed.len())
+                .filter(|&piece| allowed.has(piece))
+                .collect()
+        };
 
-    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        if item.payload.len() + 1 > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame length {} is too large", item.payload.len()),
-            ));
+        let seven = allowed_fast_set(address, &info_hash, 1313, 7);
+        assert_eq!(pieces(seven), [287, 376, 431, 808, 1059, 1188, 1217]);
+        let nine = allowed_fast_set(address, &info_hash, 1313, 9);
+        assert_eq!(
+            pieces(nine),
+            [287, 353, 376, 431, 508, 808, 1059, 1188, 1217]
+        );
+
+        // Only the /24 counts, and there can't be more than there are pieces
+        let neighbour = SocketAddrV4::new(Ipv4Addr::new(80, 4, 4, 1), 51413);
+        assert_eq!(
+            allowed_fast_set(neighbour, &info_hash, 1313, 9),
+            allowed_fast_set(address, &info_hash, 1313, 9)
+        );
+        assert!(allowed_fast_set(address, &info_hash, 3, 9).has_all());
+    }
+
+    // Starts a connection with the peer going first with `message`
+    async fn first_message(fast: bool, message: Message) -> Result<Peer<tokio::io::DuplexStream>> {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        let mut frame = BytesMut::new();
+        codec().encode(message, &mut frame).unwrap();
+        theirs.write_all(&frame).await.unwrap();
+
+        let mut handshake = Handshake::new([1; 20], [2; 20]);
+        if !fast {
+            handshake.reserved = [0; 8];
         }
+        let limits = crate::ratelimit::RateLimits::default();
+        let throttle = Throttle::new(limits.global()?, limits.torrent()?);
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+        let peer = Peer::from_stream(
+            address,
+            Transport::plain(ours),
+            &handshake,
+            &Bitfield::new(4),
+            throttle,
+        )
+        .await;
+        // Only now, what the peer sends our way would fail to go out otherwise
+        drop(theirs);
+        peer
+    }
 
-        let length = u32::to_be_bytes(item.payload.len() as u32 + 1);
+    #[tokio::test]
+    async fn fast_messages_need_fast_extension() {
+        let reject = Message::Reject {
+            index: 0,
+            begin: 0,
+            length: BLOCK_SIZE as u32,
+        };
+        let fast_only = [
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::Suggest(1),
+            Message::AllowedFast(1),
+            reject,
+        ];
+        for message in fast_only {
+            assert!(first_message(true, message.clone()).await.is_ok());
+            let error = first_message(false, message.clone()).await.err().unwrap();
+            assert!(
+                error.to_string().contains("without the fast extension"),
+                "{message:?}: {error}"
+            );
+        }
 
-        dst.reserve(4 + 1 + item.payload.len());
+        let peer = first_message(true, Message::HaveAll).await.unwrap();
+        assert!(peer.has_piece(3));
+        let peer = first_message(false, Message::Have(2)).await.unwrap();
+        assert!(peer.has_piece(2) && !peer.has_piece(3));
+    }
 
-        dst.extend_from_slice(&length);
-        dst.put_u8(item.id as u8);
-        dst.extend_from_slice(&item.payload);
+    #[tokio::test]
+    async fn bad_extended_handshake_kept() {
+        let garbage = Message::Extended {
+            id: 0,
+            payload: Bytes::from_static(b"d1:mi1e"),
+        };
+        let peer = first_message(true, garbage).await.unwrap();
+        assert!(peer.extensions.remote().is_none());
 
-        Ok(())
+        let handshake = Message::Extended {
+            id: 0,
+            payload: Bytes::from_static(b"d1:md11:lt_donthavei7eee"),
+        };
+        let peer = first_message(true, handshake).await.unwrap();
+        let remote = peer.extensions.remote().unwrap();
+        assert_eq!(remote.m.get("lt_donthave"), Some(&7));
     }
 }
@@ -0,0 +1,735 @@
+use anyhow::Result;
+use sha1::{Digest, Sha1};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddrV4;
+
+use crate::bitfield::Bitfield;
+use crate::rng::Rng;
+use crate::torrent::Torrent;
+
+pub(crate) const BLOCK_SIZE: usize = 1 << 14;
+
+// Picking the first few pieces at random gets us something to trade quickly,
+// rare pieces tend to be slow to download
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Block {
+    pub(crate) piece: u32,
+    pub(crate) begin: u32,
+    pub(crate) length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    // Only ever above one in endgame, where blocks are requested from several peers
+    Requested(usize),
+    Received,
+}
+
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<BlockState>,
+    received: usize,
+    // Who sent each block, to know who to blame if the hash is off
+    senders: Vec<Option<SocketAddrV4>>,
+}
+
+impl PartialPiece {
+    fn new(length: usize) -> Self {
+        let blocks = length.div_ceil(BLOCK_SIZE);
+        Self {
+            data: vec![0; length],
+            blocks: vec![BlockState::Open; blocks],
+            received: 0,
+            senders: vec![None; blocks],
+        }
+    }
+}
+
+// Along with whoever turned out to have sent bad data for the piece
+pub(crate) enum BlockOutcome {
+    Stored,
+    Ignored,
+    Completed(Vec<u8>, Vec<SocketAddrV4>),
+    Failed(Vec<SocketAddrV4>),
+}
+
+// A block of a piece that failed its hash check, who sent it and its hash,
+// to hold up against the good copy once we have one
+struct Suspect {
+    block: usize,
+    peer: SocketAddrV4,
+    hash: [u8; 20],
+}
+
+pub(crate) struct PiecePicker {
+    hashes: Vec<[u8; 20]>,
+    piece_length: usize,
+    length: usize,
+    wanted: Bitfield,
+    have: Bitfield,
+    completed: usize,
+    availability: Vec<usize>,
+    partial: BTreeMap<usize, PartialPiece>,
+    // Failed pieces, with everyone who sent a block for them and the blocks
+    // from pieces with more than one sender that we can't pin on anyone yet
+    tainted: HashMap<usize, HashSet<SocketAddrV4>>,
+    suspects: HashMap<usize, Vec<Suspect>>,
+    random_first: usize,
+    rng: Rng,
+}
+
+impl PiecePicker {
+    pub(crate) fn new(torrent: &Torrent) -> Result<Self> {
+        let mut picker = Self::empty(torrent);
+        for piece in 0..picker.hashes.len() {
+            picker.wanted.set(piece)?;
+        }
+
+        Ok(picker)
+    }
+
+    pub(crate) fn for_piece(torrent: &Torrent, piece: usize) -> Result<Self> {
+        let mut picker = Self::empty(torrent);
+        picker.wanted.set(piece)?;
+
+        Ok(picker)
+    }
+
+    // Nothing is wanted, for when we are only here to serve pieces
+    pub(crate) fn seeding(torrent: &Torrent) -> Self {
+        Self::empty(torrent)
+    }
+
+    fn empty(torrent: &Torrent) -> Self {
+        let pieces = torrent.info.pieces.0.len();
+        Self {
+            hashes: torrent.info.pieces.0.clone(),
+            piece_length: torrent.info.piece_length,
+            length: torrent.length(),
+            wanted: Bitfield::new(pieces),
+            have: Bitfield::new(pieces),
+            completed: 0,
+            availability: vec![0; pieces],
+            partial: BTreeMap::new(),
+            tainted: HashMap::new(),
+            suspects: HashMap::new(),
+            random_first: RANDOM_FIRST_PIECES,
+            rng: Rng::new(),
+        }
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        (0..self.hashes.len()).all(|piece| !self.needs(piece))
+    }
+
+    // Whether the peer has anything left that we still need
+    pub(crate) fn is_interesting(&self, peer: &Bitfield) -> bool {
+        (0..self.hashes.len()).any(|piece| self.needs(piece) && peer.has(piece))
+    }
+
+    pub(crate) fn peer_connected(&mut self, peer: &Bitfield) {
+        for (piece, count) in self.availability.iter_mut().enumerate() {
+            if peer.has(piece) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub(crate) fn peer_disconnected(&mut self, peer: &Bitfield) {
+        for (piece, count) in self.availability.iter_mut().enumerate() {
+            if peer.has(piece) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub(crate) fn peer_has(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    // How many connected peers have the piece
+    pub(crate) fn availability(&self, piece: usize) -> usize {
+        self.availability.get(piece).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn peer_lost(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    // Hands out up to `max` blocks the peer can serve, finishing partially
+    // downloaded pieces before starting new ones. Once everything left is in
+    // flight, blocks the peer hasn't already `requested` get handed out again.
+    pub(crate) fn pick(
+        &mut self,
+        peer: &Bitfield,
+        from: SocketAddrV4,
+        requested: &HashSet<Block>,
+        max: usize,
+    ) -> Vec<Block> {
+        let peer = &self.untainted(peer, from);
+        let mut picked = Vec::new();
+        for (&piece, partial) in self.partial.iter_mut() {
+            if picked.len() == max {
+                return picked;
+            }
+
+            if peer.has(piece) {
+                let length = partial.data.len();
+                take_open_blocks(piece, length, partial, max, &mut picked);
+            }
+        }
+
+        while picked.len() < max {
+            let Some(piece) = self.pick_piece(peer) else {
+                break;
+            };
+
+            let length = self.piece_size(piece);
+            let partial = self
+                .partial
+                .entry(piece)
+                .or_insert_with(|| PartialPiece::new(length));
+            take_open_blocks(piece, length, partial, max, &mut picked);
+        }
+
+        if picked.len() < max && self.in_endgame() {
+            self.pick_duplicates(peer, requested, max, &mut picked);
+        }
+
+        picked
+    }
+
+    // Leaves out pieces the peer already sent bad data for, as long as
+    // somebody else might have them
+    fn untainted(&self, peer: &Bitfield, from: SocketAddrV4) -> Bitfield {
+        let mut peer = peer.clone();
+        for (&piece, senders) in &self.tainted {
+            if senders.contains(&from) && self.availability[piece] > senders.len() {
+                peer.unset(piece);
+            }
+        }
+
+        peer
+    }
+
+    // Endgame is when every block we still need has been requested from someone
+    pub(crate) fn in_endgame(&self) -> bool {
+        let all_started = (0..self.hashes.len())
+            .all(|piece| !self.needs(piece) || self.partial.contains_key(&piece));
+
+        all_started
+            && self
+                .partial
+                .values()
+                .all(|partial| !partial.blocks.contains(&BlockState::Open))
+    }
+
+    // Number of peers the block is currently requested from
+    pub(crate) fn requesters(&self, block: &Block) -> usize {
+        match self
+            .partial
+            .get(&(block.piece as usize))
+            .and_then(|partial| partial.blocks.get(block.begin as usize / BLOCK_SIZE))
+        {
+            Some(BlockState::Requested(count)) => *count,
+            _ => 0,
+        }
+    }
+
+    fn pick_duplicates(
+        &mut self,
+        peer: &Bitfield,
+        requested: &HashSet<Block>,
+        max: usize,
+        picked: &mut Vec<Block>,
+    ) {
+        for (&piece, partial) in self.partial.iter_mut() {
+            if !peer.has(piece) {
+                continue;
+            }
+
+            let length = partial.data.len();
+            for (index, state) in partial.blocks.iter_mut().enumerate() {
+                if picked.len() == max {
+                    return;
+                }
+
+                let BlockState::Requested(count) = state else {
+                    continue;
+                };
+
+                let begin = index * BLOCK_SIZE;
+                let block = Block {
+                    piece: piece as u32,
+                    begin: begin as u32,
+                    length: BLOCK_SIZE.min(length - begin) as u32,
+                };
+
+                // Blocks picked a moment ago in the same call aren't in
+                // `requested` yet
+                if !requested.contains(&block) && !picked.contains(&block) {
+                    *count += 1;
+                    picked.push(block);
+                }
+            }
+        }
+    }
+
+    // Puts requested blocks back up for grabs, e.g. when a peer chokes us or goes away
+    pub(crate) fn release(&mut self, block: &Block) {
+        if let Some(partial) = self.partial.get_mut(&(block.piece as usize)) {
+            let state = &mut partial.blocks[block.begin as usize / BLOCK_SIZE];
+            match state {
+                BlockState::Requested(1) => *state = BlockState::Open,
+                BlockState::Requested(count) => *count -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    pub(crate) fn block_received(
+        &mut self,
+        block: &Block,
+        data: &[u8],
+        from: SocketAddrV4,
+    ) -> BlockOutcome {
+        let piece = block.piece as usize;
+        let Some(partial) = self.partial.get_mut(&piece) else {
+            return BlockOutcome::Ignored;
+        };
+
+        let begin = block.begin as usize;
+        let Some(state) = partial.blocks.get_mut(begin / BLOCK_SIZE) else {
+            return BlockOutcome::Ignored;
+        };
+
+        if *state == BlockState::Received
+            || !begin.is_multiple_of(BLOCK_SIZE)
+            || data.len() != BLOCK_SIZE.min(partial.data.len() - begin)
+        {
+            return BlockOutcome::Ignored;
+        }
+
+        *state = BlockState::Received;
+        partial.data[begin..begin + data.len()].copy_from_slice(data);
+        partial.received += 1;
+        partial.senders[begin / BLOCK_SIZE] = Some(from);
+        if partial.received < partial.blocks.len() {
+            return BlockOutcome::Stored;
+        }
+
+        let partial = self.partial.remove(&piece).expect("piece is partial");
+        if sha1(&partial.data) != self.hashes[piece] {
+            return BlockOutcome::Failed(self.piece_failed(piece, &partial));
+        }
+
+        self.have.set(piece).expect("piece is in range");
+        self.completed += 1;
+
+        // Now that there's a good copy, whoever sent a block that doesn't
+        // match it last time is to blame
+        self.tainted.remove(&piece);
+        let mut culprits = Vec::new();
+        for suspect in self.suspects.remove(&piece).unwrap_or_default() {
+            let begin = suspect.block * BLOCK_SIZE;
+            let end = (begin + BLOCK_SIZE).min(partial.data.len());
+            if sha1(&partial.data[begin..end]) != suspect.hash && !culprits.contains(&suspect.peer)
+            {
+                culprits.push(suspect.peer);
+            }
+        }
+
+        BlockOutcome::Completed(partial.data, culprits)
+    }
+
+    // The piece is up for grabs again. With a single sender we know who to
+    // blame straight away, otherwise it has to wait for a good copy.
+    fn piece_failed(&mut self, piece: usize, partial: &PartialPiece) -> Vec<SocketAddrV4> {
+        let senders: HashSet<SocketAddrV4> = partial.senders.iter().flatten().copied().collect();
+        self.tainted
+            .entry(piece)
+            .or_default()
+            .extend(senders.iter().copied());
+
+        if senders.len() == 1 {
+            return senders.into_iter().collect();
+        }
+
+        let suspects = self.suspects.entry(piece).or_default();
+        for (block, sender) in partial.senders.iter().enumerate() {
+            let Some(peer) = *sender else {
+                continue;
+            };
+            let begin = block * BLOCK_SIZE;
+            let end = (begin + BLOCK_SIZE).min(partial.data.len());
+            suspects.push(Suspect {
+                block,
+                peer,
+                hash: sha1(&partial.data[begin..end]),
+            });
+        }
+
+        Vec::new()
+    }
+
+    fn needs(&self, piece: usize) -> bool {
+        self.wanted.has(piece) && !self.have.has(piece)
+    }
+
+    fn piece_size(&self, piece: usize) -> usize {
+        self.piece_length
+            .min(self.length - piece * self.piece_length)
+    }
+
+    // Rarest first with ties broken at random, or entirely at random while
+    // we are still getting the first few pieces
+    fn pick_piece(&mut self, peer: &Bitfield) -> Option<usize> {
+        let random = self.completed < self.random_first;
+
+        let mut chosen = None;
+        let mut rarest = usize::MAX;
+        let mut ties = 0;
+        for piece in 0..self.hashes.len() {
+            if !self.needs(piece) || !peer.has(piece) || self.partial.contains_key(&piece) {
+                continue;
+            }
+
+            let availability = if random { 0 } else { self.availability[piece] };
+            if availability < rarest {
+                rarest = availability;
+                ties = 0;
+            }
+
+            if availability == rarest {
+                // Reservoir sampling keeps the choice uniform among equally rare pieces
+                ties += 1;
+                if self.rng.below(ties) == 0 {
+                    chosen = Some(piece);
+                }
+            }
+        }
+
+        chosen
+    }
+}
+
+fn take_open_blocks(
+    piece: usize,
+    length: usize,
+    partial: &mut PartialPiece,
+    max: usize,
+    picked: &mut Vec<Block>,
+) {
+    for (index, state) in partial.blocks.iter_mut().enumerate() {
+        if picked.len() == max {
+            break;
+        }
+
+        if *state == BlockState::Open {
+            *state = BlockState::Requested(1);
+            let begin = index * BLOCK_SIZE;
+            picked.push(Block {
+                piece: piece as u32,
+                begin: begin as u32,
+                length: BLOCK_SIZE.min(length - begin) as u32,
+            });
+        }
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    use crate::torrent::{PieceHashes, TorrentClass, TorrentInfo};
+
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE;
+    // The last piece is short, with a short last block
+    const LAST_PIECE: usize = BLOCK_SIZE + 100;
+
+    fn content(pieces: usize) -> Vec<u8> {
+        let length = (pieces - 1) * PIECE_LENGTH + LAST_PIECE;
+        (0..length).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn torrent(pieces: usize) -> Torrent {
+        let content = content(pieces);
+        Torrent {
+            announce: "http://tracker.invalid/announce".to_string(),
+            info: TorrentInfo {
+                name: "test".to_string(),
+                piece_length: PIECE_LENGTH,
+                pieces: PieceHashes(content.chunks(PIECE_LENGTH).map(sha1).collect()),
+                t_class: TorrentClass::SingleFile {
+                    length: content.len(),
+                },
+            },
+        }
+    }
+
+    // Straight to rarest first, without the random start
+    fn picker(pieces: usize) -> PiecePicker {
+        let mut picker = PiecePicker::new(&torrent(pieces)).unwrap();
+        picker.random_first = 0;
+        picker
+    }
+
+    fn peer(id: u8) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, id), 6881)
+    }
+
+    fn having(total: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(total);
+        for &piece in pieces {
+            bitfield.set(piece).unwrap();
+        }
+        bitfield
+    }
+
+    // Pieces of the blocks picked, in order
+    fn pieces(blocks: &[Block]) -> Vec<u32> {
+        blocks.iter().map(|block| block.piece).collect()
+    }
+
+    fn data(content: &[u8], block: &Block) -> Vec<u8> {
+        let start = block.piece as usize * PIECE_LENGTH + block.begin as usize;
+        content[start..start + block.length as usize].to_vec()
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut picker = picker(6);
+        for peer in [&[0, 1, 2, 3, 4, 5][..], &[0, 1, 2, 4, 5], &[0, 2, 4, 5]] {
+            picker.peer_connected(&having(6, peer));
+        }
+        // Piece 3 is at one peer, 1 at two, everything else at three
+        let all = Bitfield::full(6);
+        let picked = picker.pick(&all, peer(1), &HashSet::new(), 4);
+        assert_eq!(pieces(&picked), [3, 3, 1, 1]);
+
+        // A have makes a piece less rare
+        picker.peer_has(1);
+        picker.peer_has(1);
+        picker.peer_lost(5);
+        let picked = picker.pick(&all, peer(1), &HashSet::new(), 2);
+        assert_eq!(pieces(&picked), [5, 5]);
+    }
+
+    #[test]
+    fn rarest_ties_at_random() {
+        let torrent = torrent(6);
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            let mut picker = PiecePicker::new(&torrent).unwrap();
+            picker.random_first = 0;
+            picker.peer_connected(&having(6, &[0, 2, 3, 5]));
+            let picked = picker.pick(&Bitfield::full(6), peer(1), &HashSet::new(), 1);
+            seen.insert(picked[0].piece);
+        }
+        assert_eq!(seen, HashSet::from([1, 4]));
+    }
+
+    #[test]
+    fn random_first() {
+        let content = content(8);
+        let torrent = torrent(8);
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            let mut picker = PiecePicker::new(&torrent).unwrap();
+            picker.peer_connected(&having(8, &[0, 1, 2, 3, 4, 5, 6]));
+            let picked = picker.pick(&Bitfield::full(8), peer(1), &HashSet::new(), 1);
+            seen.insert(picked[0].piece);
+        }
+        // Piece 7 would be the only pick by rarity
+        assert!(seen.len() > 3, "{seen:?}");
+
+        // Once a few pieces are in it's rarest first again
+        let mut picker = PiecePicker::new(&torrent).unwrap();
+        picker.peer_connected(&having(8, &[0, 1, 2, 3, 4, 5, 6]));
+        for piece in 0..RANDOM_FIRST_PIECES {
+            let only = having(8, &[piece]);
+            for block in picker.pick(&only, peer(1), &HashSet::new(), 2) {
+                let outcome = picker.block_received(&block, &data(&content, &block), peer(1));
+                assert!(matches!(
+                    outcome,
+                    BlockOutcome::Stored | BlockOutcome::Completed(..)
+                ));
+            }
+        }
+        let picked = picker.pick(&Bitfield::full(8), peer(1), &HashSet::new(), 1);
+        assert_eq!(pieces(&picked), [7]);
+    }
+
+    #[test]
+    fn partial_first() {
+        let mut picker = picker(4);
+        picker.peer_connected(&having(4, &[0, 1, 3]));
+        let all = Bitfield::full(4);
+        assert_eq!(pieces(&picker.pick(&all, peer(1), &HashSet::new(), 1)), [2]);
+
+        // Piece 0 being rarer now doesn't matter until 2 is all requested
+        picker.peer_connected(&having(4, &[1, 2, 3]));
+        picker.peer_connected(&having(4, &[1, 2, 3]));
+        let picked = picker.pick(&all, peer(1), &HashSet::new(), 2);
+        assert_eq!(pieces(&picked), [2, 0]);
+        assert_eq!(picked[0].begin, BLOCK_SIZE as u32);
+    }
+
+    #[test]
+    fn last_block_is_short() {
+        let mut picker = picker(2);
+        let picked = picker.pick(&having(2, &[1]), peer(1), &HashSet::new(), 4);
+        assert_eq!(picked.len(), 2);
+        assert_eq!(picked[0].length, BLOCK_SIZE as u32);
+        assert_eq!(picked[1].length, 100);
+    }
+
+    #[test]
+    fn endgame_once_everything_is_requested() {
+        let mut picker = picker(3);
+        let all = Bitfield::full(3);
+
+        // Piece 2 hasn't been started, so no endgame yet
+        let first = picker.pick(&having(3, &[0, 1]), peer(1), &HashSet::new(), 10);
+        assert_eq!(first.len(), 4);
+        assert!(!picker.in_endgame());
+        let duplicates = picker.pick(&having(3, &[0, 1]), peer(2), &HashSet::new(), 10);
+        assert!(duplicates.is_empty());
+
+        // The last open blocks go out, then the rest is handed out again,
+        // to anyone but whoever has it already
+        let requested: HashSet<Block> = first.iter().copied().collect();
+        let last = picker.pick(&all, peer(1), &requested, 10);
+        assert_eq!(pieces(&last), [2, 2]);
+        assert!(picker.in_endgame());
+
+        let requested: HashSet<Block> = first.iter().chain(&last).copied().collect();
+        assert!(picker.pick(&all, peer(1), &requested, 10).is_empty());
+
+        let duplicates = picker.pick(&all, peer(2), &HashSet::new(), 10);
+        assert_eq!(duplicates.len(), 6);
+        for block in &duplicates {
+            assert_eq!(picker.requesters(block), 2);
+        }
+        // And once is enough for each of them
+        let requested: HashSet<Block> = duplicates.into_iter().collect();
+        assert!(picker.pick(&all, peer(2), &requested, 10).is_empty());
+    }
+
+    #[test]
+    fn release() {
+        let mut picker = picker(2);
+        let all = Bitfield::full(2);
+        let picked = picker.pick(&all, peer(1), &HashSet::new(), 4);
+        assert_eq!(picked.len(), 4);
+        assert!(picker.in_endgame());
+
+        // A reject or a timeout hands it back to whoever comes next
+        picker.release(&picked[0]);
+        assert_eq!(picker.requesters(&picked[0]), 0);
+        assert!(!picker.in_endgame());
+        let requested: HashSet<Block> = picked[1..].iter().copied().collect();
+        assert_eq!(picker.pick(&all, peer(2), &requested, 1), [picked[0]]);
+
+        // An endgame duplicate going away leaves the other request be
+        let duplicates = picker.pick(&all, peer(3), &HashSet::new(), 1);
+        assert_eq!(picker.requesters(&duplicates[0]), 2);
+        picker.release(&duplicates[0]);
+        assert_eq!(picker.requesters(&duplicates[0]), 1);
+        assert!(picker.in_endgame());
+    }
+
+    // Whatever the peer picks of `piece`, sent back with `corrupt` blocks
+    // flipped
+    fn deliver(
+        picker: &mut PiecePicker,
+        content: &[u8],
+        from: SocketAddrV4,
+        piece: usize,
+        max: usize,
+        corrupt: &[u32],
+    ) -> Vec<BlockOutcome> {
+        let only = having(picker.hashes.len(), &[piece]);
+        let picked = picker.pick(&only, from, &HashSet::new(), max);
+        picked
+            .iter()
+            .map(|block| {
+                let mut data = data(content, block);
+                if corrupt.contains(&block.begin) {
+                    data[0] ^= 0xff;
+                }
+                picker.block_received(block, &data, from)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_sender_blamed_straight_away() {
+        let content = content(2);
+        let mut picker = picker(2);
+        let mut outcomes = deliver(&mut picker, &content, peer(1), 0, 2, &[BLOCK_SIZE as u32]);
+        let Some(BlockOutcome::Failed(culprits)) = outcomes.pop() else {
+            panic!("piece should have failed");
+        };
+        assert_eq!(culprits, [peer(1)]);
+
+        // And the piece is up for grabs again
+        let outcomes = deliver(&mut picker, &content, peer(2), 0, 2, &[]);
+        let Some(BlockOutcome::Completed(data, culprits)) = outcomes.last() else {
+            panic!("piece should have completed");
+        };
+        assert_eq!(data[..], content[..PIECE_LENGTH]);
+        assert!(culprits.is_empty());
+    }
+
+    #[test]
+    fn culprit_found_once_piece_completes() {
+        let content = content(2);
+        let mut picker = picker(2);
+        for _ in 1..=3 {
+            picker.peer_connected(&Bitfield::full(2));
+        }
+
+        // One good block from 1, a bad one from 2, and nobody to blame yet
+        deliver(&mut picker, &content, peer(1), 0, 1, &[]);
+        let mut outcomes = deliver(&mut picker, &content, peer(2), 0, 1, &[BLOCK_SIZE as u32]);
+        let Some(BlockOutcome::Failed(culprits)) = outcomes.pop() else {
+            panic!("piece should have failed");
+        };
+        assert!(culprits.is_empty());
+
+        // Both of them are kept off the piece while someone else has it
+        for suspect in [peer(1), peer(2)] {
+            let picked = picker.pick(&Bitfield::full(2), suspect, &HashSet::new(), 2);
+            assert_eq!(pieces(&picked), [1, 1]);
+            for block in &picked {
+                picker.release(block);
+            }
+        }
+
+        // A good copy from 3 shows it was 2 all along
+        let outcomes = deliver(&mut picker, &content, peer(3), 0, 2, &[]);
+        let Some(BlockOutcome::Completed(_, culprits)) = outcomes.last() else {
+            panic!("piece should have completed");
+        };
+        assert_eq!(culprits, &[peer(2)]);
+        assert!(picker.tainted.is_empty());
+        assert!(picker.suspects.is_empty());
+    }
+}
@@ -0,0 +1,487 @@
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Don't bother waking up for less than this, unless that's all there is to
+// move or the limit is lower still
+const MIN_GRANT: usize = 4096;
+
+// Caps in KiB/s, everything left out is unlimited. Zero isn't a cap, leave
+// the direction out instead.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub(crate) struct RateLimits {
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    max_download: Option<u64>,
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    max_upload: Option<u64>,
+    // Upload cap while we have the whole torrent, instead of --max-upload
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    max_seed_upload: Option<u64>,
+
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    torrent_max_download: Option<u64>,
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    torrent_max_upload: Option<u64>,
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    torrent_max_seed_upload: Option<u64>,
+
+    // Global caps used instead of the ones above during --alt-schedule
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    alt_max_download: Option<u64>,
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    alt_max_upload: Option<u64>,
+    #[arg(long, value_name = "KIB/S", value_parser = clap::value_parser!(u64).range(1..))]
+    alt_max_seed_upload: Option<u64>,
+    // Daily window in UTC, like 22:00-07:00
+    #[arg(long, value_name = "HH:MM-HH:MM", value_parser = Schedule::parse)]
+    alt_schedule: Option<Schedule>,
+}
+
+impl RateLimits {
+    // Shared by every torrent
+    pub(crate) fn global(&self) -> Result<Arc<RateLimiter>> {
+        let alternate = Limits::new(
+            self.alt_max_download,
+            self.alt_max_upload,
+            self.alt_max_seed_upload,
+        )?;
+        let alternate = match self.alt_schedule {
+            Some(schedule) => Some((schedule, alternate)),
+            None => {
+                anyhow::ensure!(
+                    alternate == Limits::default(),
+                    "alternate limits need --alt-schedule"
+                );
+                None
+            }
+        };
+
+        Ok(Arc::new(RateLimiter::new(
+            Limits::new(self.max_download, self.max_upload, self.max_seed_upload)?,
+            alternate,
+        )))
+    }
+
+    pub(crate) fn torrent(&self) -> Result<Arc<RateLimiter>> {
+        Ok(Arc::new(RateLimiter::new(
+            Limits::new(
+                self.torrent_max_download,
+                self.torrent_max_upload,
+                self.torrent_max_seed_upload,
+            )?,
+            None,
+        )))
+    }
+}
+
+// In bytes a second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Limits {
+    download: Option<u64>,
+    upload: Option<u64>,
+    seeding_upload: Option<u64>,
+}
+
+impl Limits {
+    fn new(
+        download: Option<u64>,
+        upload: Option<u64>,
+        seeding_upload: Option<u64>,
+    ) -> Result<Self> {
+        let bytes = |kib: Option<u64>| -> Result<Option<u64>> {
+            kib.map(|kib| {
+                kib.checked_mul(1024)
+                    .with_context(|| format!("rate limit of {kib} KiB/s is too large"))
+            })
+            .transpose()
+        };
+        Ok(Self {
+            download: bytes(download)?,
+            upload: bytes(upload)?,
+            seeding_upload: bytes(seeding_upload)?,
+        })
+    }
+}
+
+// Seconds into the UTC day, the end being exclusive. Wrapping past midnight
+// is fine.
+#[derive(Debug, Clone, Copy)]
+struct Schedule {
+    start: u64,
+    end: u64,
+}
+
+impl Schedule {
+    fn parse(value: &str) -> Result<Self> {
+        let time = |time: &str| -> Result<u64> {
+            let (hours, minutes) = time.split_once(':').context("expected HH:MM")?;
+            let hours: u64 = hours.parse().context("parsing hours")?;
+            let minutes: u64 = minutes.parse().context("parsing minutes")?;
+            anyhow::ensure!(hours < 24 && minutes < 60, "{time} is not a time of day");
+            Ok(hours * 3600 + minutes * 60)
+        };
+
+        let (start, end) = value.split_once('-').context("expected HH:MM-HH:MM")?;
+        Ok(Self {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() % 86400);
+        self.contains(now)
+    }
+
+    fn contains(&self, now: u64) -> bool {
+        match self.start <= self.end {
+            true => self.start <= now && now < self.end,
+            false => now >= self.start || now < self.end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Download,
+    Upload,
+}
+
+// One set of caps and the buckets enforcing them
+pub(crate) struct RateLimiter {
+    limits: Limits,
+    alternate: Option<(Schedule, Limits)>,
+    download: Bucket,
+    upload: Bucket,
+    // Only means anything for a single torrent
+    seeding: AtomicBool,
+}
+
+impl RateLimiter {
+    fn new(limits: Limits, alternate: Option<(Schedule, Limits)>) -> Self {
+        Self {
+            limits,
+            alternate,
+            download: Bucket::new(),
+            upload: Bucket::new(),
+            seeding: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn set_seeding(&self, seeding: bool) {
+        self.seeding.store(seeding, Ordering::Relaxed);
+    }
+
+    fn is_seeding(&self) -> bool {
+        self.seeding.load(Ordering::Relaxed)
+    }
+
+    fn bucket(&self, direction: Direction, seeding: bool) -> (&Bucket, Option<u64>) {
+        let limits = match self.alternate {
+            Some((schedule, alternate)) if schedule.is_active() => alternate,
+            _ => self.limits,
+        };
+        match direction {
+            Direction::Download => (&self.download, limits.download),
+            Direction::Upload if seeding && limits.seeding_upload.is_some() => {
+                (&self.upload, limits.seeding_upload)
+            }
+            Direction::Upload => (&self.upload, limits.upload),
+        }
+    }
+}
+
+// Tokens are bytes, refilled at the rate and holding at most a second's
+// worth. Going into debt is fine, it just means waiting longer next time.
+struct Bucket {
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            tokens: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    fn available(&self, rate: u64) -> f64 {
+        let mut tokens = self.tokens.lock().expect("bucket lock poisoned");
+        let (available, refilled_at) = &mut *tokens;
+        let now = Instant::now();
+        let refill = rate as f64 * now.duration_since(*refilled_at).as_secs_f64();
+        *available = (*available + refill).min(rate as f64);
+        *refilled_at = now;
+        *available
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.tokens.lock().expect("bucket lock poisoned").0 -= bytes as f64;
+    }
+}
+
+// The global caps together with the ones for a single torrent
+#[derive(Clone)]
+pub(crate) struct Throttle {
+    global: Arc<RateLimiter>,
+    torrent: Arc<RateLimiter>,
+}
+
+impl Throttle {
+    pub(crate) fn new(global: Arc<RateLimiter>, torrent: Arc<RateLimiter>) -> Self {
+        Self { global, torrent }
+    }
+
+    pub(crate) fn torrent(&self) -> &RateLimiter {
+        &self.torrent
+    }
+
+    fn buckets(&self, direction: Direction) -> impl Iterator<Item = (&Bucket, u64)> {
+        let seeding = self.torrent.is_seeding();
+        [&self.global, &self.torrent]
+            .into_iter()
+            .filter_map(move |limiter| match limiter.bucket(direction, seeding) {
+                (bucket, Some(rate)) => Some((bucket, rate)),
+                (_, None) => None,
+            })
+    }
+
+    // How much can move right now, or how long until it is worth asking again
+    fn grant(&self, direction: Direction, wanted: usize) -> Result<usize, Duration> {
+        let mut granted = wanted;
+        let mut wait = Duration::ZERO;
+        for (bucket, rate) in self.buckets(direction) {
+            let available = bucket.available(rate);
+            let needed = wanted.min(MIN_GRANT).min(rate.max(1) as usize) as f64;
+            if available < needed {
+                wait = wait.max(Duration::from_secs_f64((needed - available) / rate as f64));
+            } else {
+                granted = granted.min(available as usize);
+            }
+        }
+
+        match wait.is_zero() {
+            true => Ok(granted),
+            false => Err(wait),
+        }
+    }
+
+    fn consume(&self, direction: Direction, bytes: usize) {
+        for (bucket, _) in self.buckets(direction) {
+            bucket.consume(bytes);
+        }
+    }
+}
+
+// Holds back reads and writes to stay under the caps. Sits right under the
+// codec so everything on the wire after the handshake counts.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    throttle: Throttle,
+    read_wait: Option<Pin<Box<Sleep>>>,
+    write_wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub(crate) fn new(inner: S, throttle: Throttle) -> Self {
+        Self {
+            inner,
+            throttle,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+fn poll_grant(
+    throttle: &Throttle,
+    wait: &mut Option<Pin<Box<Sleep>>>,
+    direction: Direction,
+    wanted: usize,
+    cx: &mut TaskContext<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = wait {
+            ready!(sleep.as_mut().poll(cx));
+            *wait = None;
+        }
+        match throttle.grant(direction, wanted) {
+            Ok(granted) => return Poll::Ready(granted),
+            Err(delay) => *wait = Some(Box::pin(time::sleep(delay))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let granted = ready!(poll_grant(
+            &this.throttle,
+            &mut this.read_wait,
+            Direction::Download,
+            buf.remaining(),
+            cx
+        ));
+
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(granted));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        this.throttle.consume(Direction::Download, read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let granted = ready!(poll_grant(
+            &this.throttle,
+            &mut this.write_wait,
+            Direction::Upload,
+            buf.len(),
+            cx
+        ));
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]))?;
+        this.throttle.consume(Direction::Upload, written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn at(hours: u64, minutes: u64) -> u64 {
+        hours * 3600 + minutes * 60
+    }
+
+    #[test]
+    fn schedule_within_a_day() {
+        let schedule = Schedule::parse("09:30-17:00").unwrap();
+        assert!(!schedule.contains(at(0, 0)));
+        assert!(!schedule.contains(at(9, 29)));
+        assert!(schedule.contains(at(9, 30)));
+        assert!(schedule.contains(at(16, 59)));
+        assert!(!schedule.contains(at(17, 0)));
+        assert!(!schedule.contains(at(23, 59)));
+    }
+
+    #[test]
+    fn schedule_past_midnight() {
+        let schedule = Schedule::parse("22:00-06:00").unwrap();
+        assert!(!schedule.contains(at(21, 59)));
+        assert!(schedule.contains(at(22, 0)));
+        assert!(schedule.contains(at(23, 59)));
+        assert!(schedule.contains(at(0, 0)));
+        assert!(schedule.contains(at(5, 59)));
+        assert!(!schedule.contains(at(6, 0)));
+        assert!(!schedule.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn schedule_invalid() {
+        for value in [
+            "",
+            "22:00",
+            "22:00-",
+            "2200-0600",
+            "24:00-06:00",
+            "22:60-06:00",
+            "22:00-06:-1",
+            "aa:00-06:00",
+            "22:00-06:00-07:00",
+        ] {
+            assert!(Schedule::parse(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn bucket_refills_up_to_a_second() {
+        let bucket = Bucket::new();
+        bucket.tokens.lock().unwrap().1 -= Duration::from_millis(500);
+        let available = bucket.available(1000);
+        assert!((500.0..600.0).contains(&available), "{available}");
+
+        // However long it has been, it never holds more than the rate
+        bucket.tokens.lock().unwrap().1 -= Duration::from_secs(60);
+        assert_eq!(bucket.available(1000), 1000.0);
+
+        // Debt is paid back before anything is available again
+        bucket.consume(3000);
+        assert!(bucket.available(1000) < -1900.0);
+    }
+
+    #[test]
+    fn grant_waits_for_tokens() {
+        let limits = RateLimits {
+            max_download: Some(4),
+            ..Default::default()
+        };
+        let throttle = Throttle::new(limits.global().unwrap(), limits.torrent().unwrap());
+
+        // Empty to begin with, a full minimum grant is a second away
+        let wait = throttle.grant(Direction::Download, 100_000).unwrap_err();
+        assert!(wait > Duration::from_millis(900), "{wait:?}");
+
+        // Never more than the burst
+        throttle.global.download.tokens.lock().unwrap().1 -= Duration::from_secs(60);
+        assert_eq!(throttle.grant(Direction::Download, 100_000), Ok(4096));
+
+        // Uploads aren't limited
+        assert_eq!(throttle.grant(Direction::Upload, 100_000), Ok(100_000));
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let limits = RateLimits::default();
+        let throttle = Throttle::new(limits.global().unwrap(), limits.torrent().unwrap());
+        assert_eq!(throttle.buckets(Direction::Download).count(), 0);
+        assert_eq!(throttle.buckets(Direction::Upload).count(), 0);
+        assert_eq!(
+            throttle.grant(Direction::Upload, usize::MAX),
+            Ok(usize::MAX)
+        );
+
+        let (near, far) = tokio::io::duplex(1 << 20);
+        let mut near = Throttled::new(near, throttle.clone());
+        let mut far = Throttled::new(far, throttle);
+        let data: Vec<u8> = (0..1 << 19).map(|i| i as u8).collect();
+
+        let start = Instant::now();
+        near.write_all(&data).await.unwrap();
+        let mut received = vec![0; data.len()];
+        far.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
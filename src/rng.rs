@@ -0,0 +1,34 @@
+use anyhow::{Context, Result};
+
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+
+// Good enough randomness for spreading choices around, no need for a proper
+// crate here
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new() -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+        Self(seed | 1)
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+
+        (self.0 % n as u64) as usize
+    }
+}
+
+// Key material needs the real thing though
+pub(crate) fn secure_bytes(buffer: &mut [u8]) -> Result<()> {
+    let mut urandom = File::open("/dev/urandom").context("opening /dev/urandom")?;
+    urandom
+        .read_exact(buffer)
+        .context("reading from /dev/urandom")
+}
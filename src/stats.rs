@@ -0,0 +1,234 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Rates are averaged over this many seconds
+const RATE_WINDOW: usize = 20;
+// How much a new latency sample moves the average, in eighths
+const LATENCY_WEIGHT: u64 = 1;
+
+// Per connection numbers, shared between the peer task updating them and
+// whoever wants to look at them
+#[derive(Debug)]
+pub(crate) struct PeerStats {
+    pub(crate) connected_at: Instant,
+    // Block payload only
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    // Everything on the wire after the handshake, payload included
+    received: AtomicU64,
+    sent: AtomicU64,
+    download_rate: Mutex<Rolling>,
+    upload_rate: Mutex<Rolling>,
+    // Smoothed time between requesting a block and getting it, in microseconds
+    latency: AtomicU64,
+    blocks_received: AtomicU64,
+    blocks_rejected: AtomicU64,
+    // Pieces this peer sent blocks for that then failed the hash check
+    hash_failures: AtomicU64,
+    interested: AtomicBool,
+    choked: AtomicBool,
+    snubbed: AtomicBool,
+}
+
+impl PeerStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            connected_at: Instant::now(),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            download_rate: Mutex::new(Rolling::new()),
+            upload_rate: Mutex::new(Rolling::new()),
+            latency: AtomicU64::new(0),
+            blocks_received: AtomicU64::new(0),
+            blocks_rejected: AtomicU64::new(0),
+            hash_failures: AtomicU64::new(0),
+            interested: AtomicBool::new(false),
+            choked: AtomicBool::new(true),
+            snubbed: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn age(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+
+    pub(crate) fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    // Protocol bytes that weren't block payload, each way
+    pub(crate) fn overhead_received(&self) -> u64 {
+        self.received
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.downloaded())
+    }
+
+    pub(crate) fn overhead_sent(&self) -> u64 {
+        self.sent
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.uploaded())
+    }
+
+    // Payload bytes a second over the last little while
+    pub(crate) fn download_rate(&self) -> f64 {
+        let second = self.second();
+        self.download_rate
+            .lock()
+            .expect("rate lock poisoned")
+            .rate(second)
+    }
+
+    pub(crate) fn upload_rate(&self) -> f64 {
+        let second = self.second();
+        self.upload_rate
+            .lock()
+            .expect("rate lock poisoned")
+            .rate(second)
+    }
+
+    pub(crate) fn latency(&self) -> Option<Duration> {
+        Some(self.latency.load(Ordering::Relaxed))
+            .filter(|&micros| micros != 0)
+            .map(Duration::from_micros)
+    }
+
+    pub(crate) fn blocks_received(&self) -> u64 {
+        self.blocks_received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn blocks_rejected(&self) -> u64 {
+        self.blocks_rejected.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn hash_failures(&self) -> u64 {
+        self.hash_failures.load(Ordering::Relaxed)
+    }
+
+    // Whether the peer wants something from us
+    pub(crate) fn is_interested(&self) -> bool {
+        self.interested.load(Ordering::Relaxed)
+    }
+
+    // Whether we are refusing to upload to the peer
+    pub(crate) fn is_choked(&self) -> bool {
+        self.choked.load(Ordering::Relaxed)
+    }
+
+    // Whether the peer is sitting on our requests without sending anything
+    pub(crate) fn is_snubbed(&self) -> bool {
+        self.snubbed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        let second = self.second();
+        self.download_rate
+            .lock()
+            .expect("rate lock poisoned")
+            .add(second, bytes as u64);
+    }
+
+    pub(crate) fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        let second = self.second();
+        self.upload_rate
+            .lock()
+            .expect("rate lock poisoned")
+            .add(second, bytes as u64);
+    }
+
+    pub(crate) fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn block_received(&self, latency: Option<Duration>) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+
+        let Some(latency) = latency else {
+            return;
+        };
+        let sample = (latency.as_micros() as u64).max(1);
+        let average = self.latency.load(Ordering::Relaxed);
+        let average = match average {
+            0 => sample,
+            average => (average * (8 - LATENCY_WEIGHT) + sample * LATENCY_WEIGHT) / 8,
+        };
+        self.latency.store(average.max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn block_rejected(&self) {
+        self.blocks_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn hash_failed(&self) {
+        self.hash_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_interested(&self, interested: bool) {
+        self.interested.store(interested, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_choked(&self, choked: bool) {
+        self.choked.store(choked, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_snubbed(&self, snubbed: bool) {
+        self.snubbed.store(snubbed, Ordering::Relaxed);
+    }
+
+    fn second(&self) -> u64 {
+        self.connected_at.elapsed().as_secs()
+    }
+}
+
+// Bytes per second for the last RATE_WINDOW seconds, indexed by seconds
+// since the connection started
+#[derive(Debug)]
+struct Rolling {
+    seconds: [u64; RATE_WINDOW],
+    current: u64,
+}
+
+impl Rolling {
+    fn new() -> Self {
+        Self {
+            seconds: [0; RATE_WINDOW],
+            current: 0,
+        }
+    }
+
+    fn advance(&mut self, second: u64) {
+        if second <= self.current {
+            return;
+        }
+
+        let stale = (second - self.current).min(RATE_WINDOW as u64);
+        for gone in 1..=stale {
+            self.seconds[((self.current + gone) % RATE_WINDOW as u64) as usize] = 0;
+        }
+        self.current = second;
+    }
+
+    fn add(&mut self, second: u64, bytes: u64) {
+        self.advance(second);
+        self.seconds[(second % RATE_WINDOW as u64) as usize] += bytes;
+    }
+
+    fn rate(&mut self, second: u64) -> f64 {
+        self.advance(second);
+        // Young connections haven't had the whole window yet
+        let span = (second + 1).min(RATE_WINDOW as u64);
+        self.seconds.iter().sum::<u64>() as f64 / span as f64
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::bitfield::Bitfield;
+use crate::torrent::{Torrent, TorrentClass};
+
+// A file on disk holding the bytes of the torrent from `offset` onwards
+struct Segment {
+    file: File,
+    offset: usize,
+    length: usize,
+}
+
+// Maps the torrent's contiguous byte range onto the files backing it, so
+// callers only ever deal in pieces
+pub(crate) struct Storage {
+    segments: Vec<Segment>,
+    piece_length: usize,
+    length: usize,
+}
+
+impl Storage {
+    // Single file torrents are stored at `path` itself, multi-file ones in a
+    // directory at `path`
+    pub(crate) fn open(torrent: &Torrent, path: &Path) -> Result<Self> {
+        let mut segments = Vec::new();
+        for (file_path, offset, length) in layout(torrent, path)? {
+            if let Some(parent) = file_path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("creating {}", parent.display()))?;
+            }
+
+            segments.push(Segment {
+                file: open_file(&file_path, length)?,
+                offset,
+                length,
+            });
+        }
+
+        Ok(Self {
+            segments,
+            piece_length: torrent.info.piece_length,
+            length: torrent.length(),
+        })
+    }
+
+    // Read-only view of data that is already there, for seeding. Missing files
+    // are skipped so the pieces they cover just fail to verify.
+    pub(crate) fn existing(torrent: &Torrent, path: &Path) -> Result<Self> {
+        let segments = layout(torrent, path)?
+            .into_iter()
+            .filter_map(|(file_path, offset, length)| {
+                let file = File::open(file_path).ok()?;
+                Some(Segment {
+                    file,
+                    offset,
+                    length,
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            segments,
+            piece_length: torrent.info.piece_length,
+            length: torrent.length(),
+        })
+    }
+
+    // Stores nothing but a single piece at `path`
+    pub(crate) fn for_piece(torrent: &Torrent, piece: usize, path: &Path) -> Result<Self> {
+        let length = torrent.piece_length(piece);
+        let segment = Segment {
+            file: open_file(path, length)?,
+            offset: piece * torrent.info.piece_length,
+            length,
+        };
+
+        Ok(Self {
+            segments: vec![segment],
+            piece_length: torrent.info.piece_length,
+            length: torrent.length(),
+        })
+    }
+
+    pub(crate) fn read(&self, piece: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        let start = piece * self.piece_length + begin;
+        anyhow::ensure!(
+            start + length <= self.length,
+            "reading past the end of the torrent"
+        );
+
+        let mut buffer = vec![0; length];
+        self.for_each_segment(start, length, |segment, file_offset, range| {
+            segment
+                .file
+                .read_exact_at(&mut buffer[range], file_offset as u64)
+        })
+        .with_context(|| format!("reading {length} bytes at {begin} of piece {piece}"))?;
+
+        Ok(buffer)
+    }
+
+    pub(crate) fn write_piece(&self, piece: usize, data: &[u8]) -> Result<()> {
+        let start = piece * self.piece_length;
+        self.for_each_segment(start, data.len(), |segment, file_offset, range| {
+            segment.file.write_all_at(&data[range], file_offset as u64)
+        })
+        .with_context(|| format!("writing piece {piece}"))
+    }
+
+    // Hashes whatever is already on disk to find the pieces we can serve
+    pub(crate) fn verify(&self, torrent: &Torrent) -> Result<Bitfield> {
+        let mut have = Bitfield::new(torrent.info.pieces.0.len());
+        for (piece, expected) in torrent.info.pieces.0.iter().enumerate() {
+            // Missing or short files just mean we don't have those pieces
+            let Ok(data) = self.read(piece, 0, torrent.piece_length(piece)) else {
+                continue;
+            };
+
+            let mut hasher = Sha1::new();
+            hasher.update(&data);
+            let hash: [u8; 20] = hasher.finalize().into();
+            if hash == *expected {
+                have.set(piece)?;
+            }
+        }
+
+        Ok(have)
+    }
+
+    // Calls `f` for every segment overlapping `length` bytes at torrent offset
+    // `start`, with where to go in the file and which part of the buffer it covers
+    fn for_each_segment(
+        &self,
+        start: usize,
+        length: usize,
+        mut f: impl FnMut(&Segment, usize, std::ops::Range<usize>) -> std::io::Result<()>,
+    ) -> Result<()> {
+        let end = start + length;
+        let mut covered = 0;
+        for segment in &self.segments {
+            let segment_end = segment.offset + segment.length;
+            if segment_end <= start || segment.offset >= end {
+                continue;
+            }
+
+            let from = start.max(segment.offset);
+            let to = end.min(segment_end);
+            f(segment, from - segment.offset, from - start..to - start)?;
+            covered += to - from;
+        }
+
+        anyhow::ensure!(covered == length, "range is not backed by storage");
+
+        Ok(())
+    }
+}
+
+// Where each file of the torrent lives on disk, along with its offset and length
+// within the torrent
+fn layout(torrent: &Torrent, path: &Path) -> Result<Vec<(PathBuf, usize, usize)>> {
+    match &torrent.info.t_class {
+        TorrentClass::SingleFile { length } => Ok(vec![(path.to_path_buf(), 0, *length)]),
+        TorrentClass::MultiFile { files } => {
+            let mut offset = 0;
+            files
+                .iter()
+                .map(|file| {
+                    let mut file_path = path.to_path_buf();
+                    for component in &file.path {
+                        // Anything but a plain name could land outside `path`
+                        let mut parts = Path::new(component).components();
+                        anyhow::ensure!(
+                            matches!(
+                                (parts.next(), parts.next()),
+                                (Some(Component::Normal(_)), None)
+                            ),
+                            "torrent has an unsafe file path {:?}",
+                            file.path
+                        );
+                        file_path.push(component);
+                    }
+                    let entry = (file_path, offset, file.length);
+                    offset += file.length;
+                    Ok(entry)
+                })
+                .collect()
+        }
+    }
+}
+
+fn open_file(path: &Path, length: usize) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("opening {}", path.display()))?;
+
+    let current = file.metadata().context("reading file metadata")?.len();
+    if current != length as u64 {
+        file.set_len(length as u64)
+            .with_context(|| format!("allocating {}", path.display()))?;
+    }
+
+    Ok(file)
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+use crate::bitfield::Bitfield;
+use crate::picker::PiecePicker;
+use crate::rng::Rng;
+
+// BEP 16. Peers are told we have nothing and then get shown one piece at a
+// time, each of them a different one where possible. A peer only gets shown
+// another once the last piece it was shown turns up at some other peer, so
+// what we upload gets passed on instead of downloaded from us again.
+pub(crate) struct SuperSeed {
+    // What each peer was last shown, until it is seen elsewhere
+    offered: HashMap<SocketAddrV4, usize>,
+    // How many times each piece has been shown to anyone
+    offers: Vec<usize>,
+    rng: Rng,
+}
+
+impl SuperSeed {
+    pub(crate) fn new(pieces: usize) -> Self {
+        Self {
+            offered: HashMap::new(),
+            offers: vec![0; pieces],
+            rng: Rng::new(),
+        }
+    }
+
+    // The least shown piece the peer is missing, rarest among those and then
+    // at random. None if there's nothing left to show it.
+    pub(crate) fn offer(
+        &mut self,
+        peer: SocketAddrV4,
+        theirs: &Bitfield,
+        ours: &Bitfield,
+        picker: &PiecePicker,
+    ) -> Option<usize> {
+        let mut chosen = None;
+        let mut best = (usize::MAX, usize::MAX);
+        let mut ties = 0;
+        for piece in 0..self.offers.len() {
+            if !ours.has(piece) || theirs.has(piece) {
+                continue;
+            }
+
+            let rank = (self.offers[piece], picker.availability(piece));
+            if rank < best {
+                best = rank;
+                ties = 0;
+            }
+            if rank == best {
+                ties += 1;
+                if self.rng.below(ties) == 0 {
+                    chosen = Some(piece);
+                }
+            }
+        }
+
+        let piece = chosen?;
+        self.offers[piece] += 1;
+        self.offered.insert(peer, piece);
+
+        Some(piece)
+    }
+
+    // `peer` has `piece` now. Whoever else was shown it has passed it on and
+    // can be shown another.
+    pub(crate) fn piece_seen(&mut self, peer: SocketAddrV4, piece: usize) -> Vec<SocketAddrV4> {
+        let passed_on: Vec<SocketAddrV4> = self
+            .offered
+            .iter()
+            .filter(|&(&shown, &offered)| shown != peer && offered == piece)
+            .map(|(&shown, _)| shown)
+            .collect();
+        for shown in &passed_on {
+            self.offered.remove(shown);
+        }
+
+        passed_on
+    }
+
+    pub(crate) fn peer_disconnected(&mut self, peer: &SocketAddrV4) {
+        self.offered.remove(peer);
+    }
+}
@@ -0,0 +1,416 @@
+use anyhow::{Context, Result};
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::bitfield::Bitfield;
+use crate::client::Client;
+use crate::picker::{Block, PiecePicker};
+use crate::ratelimit::Throttle;
+use crate::stats::PeerStats;
+use crate::storage::Storage;
+use crate::superseed::SuperSeed;
+use crate::torrent::Torrent;
+
+// Pieces failing their hash because of a peer before it gets banned
+const MAX_STRIKES: u32 = 3;
+
+// Things other parts of the client need a peer connection to do
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PeerCommand {
+    Choke,
+    Unchoke,
+    Ban,
+    Disconnect,
+    // The last piece shown while super-seeding got passed on, show another
+    Reveal,
+}
+
+#[derive(Clone)]
+pub(crate) struct PeerHandle {
+    pub(crate) address: SocketAddrV4,
+    pub(crate) client: Client,
+    pub(crate) stats: Arc<PeerStats>,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+}
+
+impl PeerHandle {
+    pub(crate) fn new(
+        address: SocketAddrV4,
+        client: Client,
+        stats: Arc<PeerStats>,
+        commands: mpsc::UnboundedSender<PeerCommand>,
+    ) -> Self {
+        Self {
+            address,
+            client,
+            stats,
+            commands,
+        }
+    }
+
+    pub(crate) fn send(&self, command: PeerCommand) {
+        // The peer might be on its way out, which is fine
+        let _ = self.commands.send(command);
+    }
+}
+
+// State shared by every peer connection working on the same torrent
+pub(crate) struct Swarm {
+    torrent: Torrent,
+    picker: Mutex<PiecePicker>,
+    storage: Storage,
+    // Pieces that are verified and on disk, so safe to hand out
+    have: Mutex<Bitfield>,
+    peers: Mutex<HashMap<SocketAddrV4, PeerHandle>>,
+    // Bad pieces by address, and those who had too many of them. Kept for
+    // as long as we are running, whether they stay connected or not.
+    strikes: Mutex<HashMap<Ipv4Addr, u32>>,
+    banned: Mutex<HashSet<Ipv4Addr>>,
+    throttle: Throttle,
+    super_seed: Mutex<Option<SuperSeed>>,
+    // Where we accept incoming peers, zero when we don't
+    port: AtomicU16,
+    interest: Notify,
+    changes: watch::Sender<u64>,
+    cancels: broadcast::Sender<Block>,
+    haves: broadcast::Sender<usize>,
+    completed: mpsc::UnboundedSender<usize>,
+}
+
+impl Swarm {
+    pub(crate) fn new(
+        torrent: &Torrent,
+        picker: PiecePicker,
+        storage: Storage,
+        have: Bitfield,
+        throttle: Throttle,
+        completed: mpsc::UnboundedSender<usize>,
+    ) -> Self {
+        throttle.torrent().set_seeding(have.has_all());
+        Self {
+            torrent: torrent.clone(),
+            picker: Mutex::new(picker),
+            storage,
+            have: Mutex::new(have),
+            peers: Mutex::new(HashMap::new()),
+            strikes: Mutex::new(HashMap::new()),
+            banned: Mutex::new(HashSet::new()),
+            throttle,
+            super_seed: Mutex::new(None),
+            port: AtomicU16::new(0),
+            interest: Notify::new(),
+            changes: watch::Sender::new(0),
+            cancels: broadcast::Sender::new(256),
+            haves: broadcast::Sender::new(256),
+            completed,
+        }
+    }
+
+    pub(crate) fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    pub(crate) fn picker(&self) -> MutexGuard<'_, PiecePicker> {
+        self.picker.lock().expect("picker lock poisoned")
+    }
+
+    pub(crate) fn bitfield(&self) -> Bitfield {
+        self.have.lock().expect("have lock poisoned").clone()
+    }
+
+    // What new peers get told we have, nothing at all while super-seeding
+    pub(crate) fn advertised(&self) -> Bitfield {
+        match self.is_super_seeding() {
+            true => Bitfield::new(self.torrent.info.pieces.0.len()),
+            false => self.bitfield(),
+        }
+    }
+
+    pub(crate) fn has_piece(&self, piece: usize) -> bool {
+        self.have.lock().expect("have lock poisoned").has(piece)
+    }
+
+    // Every connection for this torrent goes through it
+    pub(crate) fn throttle(&self) -> Throttle {
+        self.throttle.clone()
+    }
+
+    pub(crate) fn set_port(&self, port: u16) {
+        self.port.store(port, Ordering::Relaxed);
+    }
+
+    pub(crate) fn port(&self) -> Option<u16> {
+        Some(self.port.load(Ordering::Relaxed)).filter(|&port| port != 0)
+    }
+
+    pub(crate) fn peer_connected(&self, handle: PeerHandle) {
+        self.peers
+            .lock()
+            .expect("peers lock poisoned")
+            .insert(handle.address, handle);
+    }
+
+    pub(crate) fn peer_disconnected(&self, address: &SocketAddrV4) {
+        self.peers
+            .lock()
+            .expect("peers lock poisoned")
+            .remove(address);
+        if let Some(super_seed) = self
+            .super_seed
+            .lock()
+            .expect("super seed lock poisoned")
+            .as_mut()
+        {
+            super_seed.peer_disconnected(address);
+        }
+    }
+
+    pub(crate) fn disconnect(&self, address: &SocketAddrV4) {
+        if let Some(peer) = self.peers.lock().expect("peers lock poisoned").get(address) {
+            peer.send(PeerCommand::Disconnect);
+        }
+    }
+
+    pub(crate) fn peers(&self) -> Vec<PeerHandle> {
+        self.peers
+            .lock()
+            .expect("peers lock poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    // Lets the choker know someone new wants to download from us
+    pub(crate) fn peer_interested(&self) {
+        self.interest.notify_one();
+    }
+
+    pub(crate) async fn interest_changed(&self) {
+        self.interest.notified().await;
+    }
+
+    // Peers idling because there was nothing to request wait on this
+    pub(crate) fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    pub(crate) fn changed(&self) {
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+
+    // Endgame blocks requested from several peers get cancelled everywhere
+    // else as soon as one of them delivers
+    pub(crate) fn cancels(&self) -> broadcast::Receiver<Block> {
+        self.cancels.subscribe()
+    }
+
+    pub(crate) fn cancel(&self, block: Block) {
+        // Nobody listening just means nobody else is downloading
+        let _ = self.cancels.send(block);
+    }
+
+    // Pieces we just finished, to be announced to every peer
+    pub(crate) fn haves(&self) -> broadcast::Receiver<usize> {
+        self.haves.subscribe()
+    }
+
+    pub(crate) fn read_block(&self, block: &Block) -> Result<Vec<u8>> {
+        self.storage.read(
+            block.piece as usize,
+            block.begin as usize,
+            block.length as usize,
+        )
+    }
+
+    pub(crate) fn piece_completed(&self, piece: usize, data: Vec<u8>) -> Result<()> {
+        self.storage.write_piece(piece, &data)?;
+        let mut have = self.have.lock().expect("have lock poisoned");
+        have.set(piece)?;
+        self.throttle.torrent().set_seeding(have.has_all());
+        drop(have);
+
+        let _ = self.haves.send(piece);
+        self.completed
+            .send(piece)
+            .context("nobody is waiting for completed pieces")?;
+        self.changed();
+
+        Ok(())
+    }
+
+    pub(crate) fn enable_super_seeding(&self) {
+        let pieces = self.torrent.info.pieces.0.len();
+        *self.super_seed.lock().expect("super seed lock poisoned") = Some(SuperSeed::new(pieces));
+    }
+
+    pub(crate) fn is_super_seeding(&self) -> bool {
+        self.super_seed
+            .lock()
+            .expect("super seed lock poisoned")
+            .is_some()
+    }
+
+    // The next piece to show a peer while super-seeding
+    pub(crate) fn offer(&self, peer: SocketAddrV4, theirs: &Bitfield) -> Option<usize> {
+        let ours = self.bitfield();
+        let picker = self.picker();
+        self.super_seed
+            .lock()
+            .expect("super seed lock poisoned")
+            .as_mut()?
+            .offer(peer, theirs, &ours, &picker)
+    }
+
+    // A peer announced a piece, which may be one we showed somebody else
+    pub(crate) fn piece_seen(&self, peer: SocketAddrV4, piece: usize) {
+        let passed_on = match self
+            .super_seed
+            .lock()
+            .expect("super seed lock poisoned")
+            .as_mut()
+        {
+            Some(super_seed) => super_seed.piece_seen(peer, piece),
+            None => return,
+        };
+
+        for handle in self.peers() {
+            if passed_on.contains(&handle.address) {
+                handle.send(PeerCommand::Reveal);
+            }
+        }
+    }
+
+    pub(crate) fn is_banned(&self, ip: &Ipv4Addr) -> bool {
+        self.banned
+            .lock()
+            .expect("banned lock poisoned")
+            .contains(ip)
+    }
+
+    // Holds bad data against the peers that sent it, kicking out any that
+    // have done it too often
+    pub(crate) fn strike(&self, culprits: &[SocketAddrV4]) {
+        for culprit in culprits {
+            let strikes = {
+                let mut strikes = self.strikes.lock().expect("strikes lock poisoned");
+                let count = strikes.entry(*culprit.ip()).or_default();
+                *count += 1;
+                *count
+            };
+            if strikes >= MAX_STRIKES {
+                self.banned
+                    .lock()
+                    .expect("banned lock poisoned")
+                    .insert(*culprit.ip());
+            }
+
+            for peer in self.peers() {
+                if peer.address.ip() != culprit.ip() {
+                    continue;
+                }
+                if peer.address == *culprit {
+                    peer.stats.hash_failed();
+                }
+                if strikes >= MAX_STRIKES {
+                    peer.send(PeerCommand::Ban);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sha1::{Digest, Sha1};
+
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    use crate::picker::{BlockOutcome, BLOCK_SIZE};
+    use crate::ratelimit::RateLimits;
+    use crate::torrent::{PieceHashes, TorrentClass, TorrentInfo};
+
+    const LENGTH: usize = 2 * BLOCK_SIZE;
+
+    fn content() -> Vec<u8> {
+        (0..LENGTH).map(|i| (i % 251) as u8).collect()
+    }
+
+    // A single piece of two blocks, with nothing on disk
+    fn swarm() -> (Swarm, mpsc::UnboundedReceiver<usize>) {
+        let torrent = Torrent {
+            announce: "http://tracker.invalid/announce".to_string(),
+            info: TorrentInfo {
+                name: "test".to_string(),
+                piece_length: LENGTH,
+                pieces: PieceHashes(vec![Sha1::digest(content()).into()]),
+                t_class: TorrentClass::SingleFile { length: LENGTH },
+            },
+        };
+        let storage = Storage::existing(&torrent, Path::new("/nonexistent/test")).unwrap();
+        let limits = RateLimits::default();
+        let throttle = Throttle::new(limits.global().unwrap(), limits.torrent().unwrap());
+        let (tx, completed) = mpsc::unbounded_channel();
+        let picker = PiecePicker::new(&torrent).unwrap();
+        let swarm = Swarm::new(&torrent, picker, storage, Bitfield::new(1), throttle, tx);
+
+        (swarm, completed)
+    }
+
+    fn peer(id: u8) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, id), 6881)
+    }
+
+    // Both blocks from `from`, the second one corrupted, struck the way
+    // the peer connection does it
+    fn send_bad_piece(swarm: &Swarm, from: SocketAddrV4) {
+        let blocks = swarm
+            .picker()
+            .pick(&Bitfield::full(1), from, &HashSet::new(), 2);
+        assert_eq!(blocks.len(), 2);
+
+        let content = content();
+        for block in blocks {
+            let begin = block.begin as usize;
+            let mut data = content[begin..begin + block.length as usize].to_vec();
+            if begin > 0 {
+                data[0] ^= 0xff;
+            }
+            let outcome = swarm.picker().block_received(&block, &data, from);
+            if let BlockOutcome::Failed(culprits) = outcome {
+                assert_eq!(culprits, [from]);
+                swarm.strike(&culprits);
+            }
+        }
+    }
+
+    #[test]
+    fn banned_after_repeated_bad_pieces() {
+        let (swarm, _completed) = swarm();
+        let (tx, mut commands) = mpsc::unbounded_channel();
+        let stats = Arc::new(PeerStats::new());
+        swarm.peer_connected(PeerHandle::new(
+            peer(1),
+            Client::identify(&[0; 20]),
+            stats.clone(),
+            tx,
+        ));
+
+        for strike in 1..=MAX_STRIKES {
+            assert!(!swarm.is_banned(peer(1).ip()));
+            send_bad_piece(&swarm, peer(1));
+            assert_eq!(stats.hash_failures(), strike as u64);
+        }
+        assert!(swarm.is_banned(peer(1).ip()));
+        assert!(matches!(commands.try_recv(), Ok(PeerCommand::Ban)));
+
+        // Nobody else is held to account for it
+        assert!(!swarm.is_banned(peer(2).ip()));
+    }
+}
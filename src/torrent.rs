@@ -33,6 +33,12 @@ impl Torrent {
             TorrentClass::MultiFile { files } => files.iter().map(|file| file.length).sum(),
         }
     }
+
+    // Every piece is the same size apart from the last one, which gets whatever is left
+    pub(crate) fn piece_length(&self, piece_id: usize) -> usize {
+        let start = piece_id * self.info.piece_length;
+        self.info.piece_length.min(self.length() - start)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -57,8 +63,8 @@ pub(crate) enum TorrentClass {
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct TFile {
-    length: usize,
-    path: Vec<String>,
+    pub(crate) length: usize,
+    pub(crate) path: Vec<String>,
 }
 
 // NOTE: Tips on Deserialzing from https://serde.rs/impl-deserialize.html
@@ -78,7 +84,7 @@ impl<'de> Visitor<'de> for PieceHashesVisitor {
     where
         E: de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::custom(format!("length is {}", v.len())));
         }
 
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::net::SocketAddrV4;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::client::Client;
+use crate::peer::{Handshake, Message};
+
+// Payloads longer than this only have their start written out
+const MAX_PAYLOAD: usize = 64;
+
+// Every handshake and message on every connection, one JSON object a line
+pub(crate) struct Tracer {
+    file: Mutex<LineWriter<File>>,
+}
+
+static TRACER: OnceLock<Option<Tracer>> = OnceLock::new();
+
+// Set once on startup, connections pick it up as they come up
+pub(crate) fn configure(path: Option<&Path>) -> Result<()> {
+    let tracer = match path {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("creating trace file {}", path.display()))?;
+            Some(Tracer {
+                file: Mutex::new(LineWriter::new(file)),
+            })
+        }
+        None => None,
+    };
+    TRACER
+        .set(tracer)
+        .map_err(|_| anyhow::anyhow!("tracing should only be configured once"))
+}
+
+fn get() -> Option<&'static Tracer> {
+    TRACER.get_or_init(|| None).as_ref()
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Default, Serialize)]
+struct Record {
+    // Microseconds since the epoch
+    time: u128,
+    peer: String,
+    direction: Option<Direction>,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    // On the wire, length prefix included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    piece: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    begin: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extension: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reserved: Option<String>,
+    // Hex, cut short past MAX_PAYLOAD bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// The tracer as one connection sees it, a no-op when tracing is off
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Trace {
+    peer: SocketAddrV4,
+    enabled: bool,
+}
+
+impl Trace {
+    pub(crate) fn new(peer: SocketAddrV4) -> Self {
+        Self {
+            peer,
+            enabled: get().is_some(),
+        }
+    }
+
+    pub(crate) fn handshake(&self, direction: Direction, handshake: &Handshake) {
+        if !self.enabled {
+            return;
+        }
+
+        self.write(Record {
+            direction: Some(direction),
+            kind: "handshake",
+            size: Some(Handshake::LENGTH),
+            info_hash: Some(hex::encode(handshake.info_hash)),
+            peer_id: Some(hex::encode(handshake.peer_id)),
+            client: Some(Client::identify(&handshake.peer_id).to_string()),
+            reserved: Some(hex::encode(handshake.reserved)),
+            ..Default::default()
+        });
+    }
+
+    pub(crate) fn message(&self, direction: Direction, message: &Message) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut record = Record {
+            direction: Some(direction),
+            size: Some(message.wire_length()),
+            ..Default::default()
+        };
+        record.kind = match message {
+            Message::KeepAlive => "keep_alive",
+            Message::Choke => "choke",
+            Message::Unchoke => "unchoke",
+            Message::Interested => "interested",
+            Message::NotInterested => "not_interested",
+            Message::Have(piece) => {
+                record.piece = Some(*piece);
+                "have"
+            }
+            Message::Bitfield(bitfield) => {
+                record.set_payload(bitfield);
+                "bitfield"
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                record.set_block(*index, *begin, *length);
+                "request"
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                record.set_block(*index, *begin, block.len() as u32);
+                record.set_payload(block);
+                "piece"
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                record.set_block(*index, *begin, *length);
+                "cancel"
+            }
+            Message::Suggest(piece) => {
+                record.piece = Some(*piece);
+                "suggest"
+            }
+            Message::HaveAll => "have_all",
+            Message::HaveNone => "have_none",
+            Message::Reject {
+                index,
+                begin,
+                length,
+            } => {
+                record.set_block(*index, *begin, *length);
+                "reject"
+            }
+            Message::AllowedFast(piece) => {
+                record.piece = Some(*piece);
+                "allowed_fast"
+            }
+            Message::Extended { id, payload } => {
+                record.extension = Some(*id);
+                record.set_payload(payload);
+                "extended"
+            }
+        };
+
+        self.write(record);
+    }
+
+    // Whatever made us give up on reading from the peer
+    pub(crate) fn error(&self, error: &dyn std::fmt::Display) {
+        if !self.enabled {
+            return;
+        }
+
+        self.write(Record {
+            direction: Some(Direction::Received),
+            kind: "error",
+            error: Some(error.to_string()),
+            ..Default::default()
+        });
+    }
+
+    fn write(&self, mut record: Record) {
+        let Some(tracer) = get() else {
+            return;
+        };
+        record.time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        record.peer = self.peer.to_string();
+
+        let Ok(mut line) = serde_json::to_vec(&record) else {
+            return;
+        };
+        line.push(b'\n');
+        // A trace that can't be written shouldn't take the connection down
+        let mut file = tracer.file.lock().expect("trace lock poisoned");
+        let _ = file.write_all(&line);
+    }
+}
+
+impl Record {
+    fn set_block(&mut self, index: u32, begin: u32, length: u32) {
+        self.piece = Some(index);
+        self.begin = Some(begin);
+        self.length = Some(length);
+    }
+
+    fn set_payload(&mut self, payload: &[u8]) {
+        self.truncated = payload.len() > MAX_PAYLOAD;
+        self.payload = Some(hex::encode(&payload[..payload.len().min(MAX_PAYLOAD)]));
+    }
+}
@@ -2,12 +2,25 @@ use anyhow::{Context, Result};
 use serde::de::{Deserializer, Visitor};
 use serde::{Deserialize, Serialize};
 
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
 use std::net::{Ipv4Addr, SocketAddrV4};
 
+use crate::peer::peer_id;
+use crate::proxy::{self, ProxyKind};
 use crate::torrent::Torrent;
 
+pub(crate) const PORT: u16 = 6881;
+
 pub(crate) struct TrackerClient;
 
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Event {
+    Started,
+    Stopped,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct TrackerRequest {
     peer_id: String,
@@ -16,22 +29,35 @@ struct TrackerRequest {
     downloaded: usize,
     left: usize,
     compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<Event>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub(crate) struct TrackerResponse {
+    pub(crate) interval: Option<u64>,
     pub(crate) peers: Peers,
 }
 
 impl TrackerClient {
     pub(crate) async fn peers(torrent: &Torrent) -> Result<TrackerResponse> {
+        Self::announce(torrent, PORT, torrent.length(), None).await
+    }
+
+    pub(crate) async fn announce(
+        torrent: &Torrent,
+        port: u16,
+        left: usize,
+        event: Option<Event>,
+    ) -> Result<TrackerResponse> {
         let tracker_request = TrackerRequest {
-            peer_id: "00112233445566778899".to_string(),
-            port: 6881,
+            peer_id: String::from_utf8_lossy(peer_id()).into_owned(),
+            port,
             uploaded: 0,
             downloaded: 0,
-            left: torrent.length(),
+            left,
             compact: 1,
+            event,
         };
         let info_hash = torrent.info_hash()?;
         let encoded = urlencode(&info_hash);
@@ -42,7 +68,7 @@ impl TrackerClient {
             encoded
         );
 
-        let response = reqwest::get(&url).await?.bytes().await?;
+        let response = fetch(&url).await.context("contacting tracker")?;
         let tracker_response: TrackerResponse =
             serde_bencode::from_bytes(&response).context("deserializing tracker response")?;
 
@@ -50,6 +76,55 @@ impl TrackerClient {
     }
 }
 
+async fn fetch(url: &str) -> Result<Vec<u8>> {
+    let response = match proxy::get() {
+        None => reqwest::get(url).await?,
+        Some(proxy) if proxy.kind == ProxyKind::Http => {
+            let client = reqwest::Client::builder().proxy(proxy.reqwest()?).build()?;
+            client.get(url).send().await?
+        }
+        // reqwest can't do SOCKS here, so plain HTTP it is
+        Some(_) => return fetch_tunneled(url).await,
+    };
+
+    Ok(response.error_for_status()?.bytes().await?.to_vec())
+}
+
+// Bare bones HTTP/1.0 GET over a proxied connection, which is all an
+// announce needs
+async fn fetch_tunneled(url: &str) -> Result<Vec<u8>> {
+    let url = reqwest::Url::parse(url).context("parsing tracker URL")?;
+    anyhow::ensure!(
+        url.scheme() == "http",
+        "only http:// trackers can be reached through a SOCKS5 proxy"
+    );
+    let host = url.host_str().context("tracker URL has no host")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let mut stream = proxy::connect(host, port).await?;
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+    let request = format!("GET {path} HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .context("tracker response has no end of headers")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or_default();
+    anyhow::ensure!(
+        status.split_whitespace().nth(1) == Some("200"),
+        "tracker replied {status}"
+    );
+
+    Ok(response.split_off(split + 4))
+}
+
 // TODO: Same dance for hashes - implement Visitor etc
 
 #[derive(Debug, Clone)]
@@ -67,7 +142,7 @@ impl<'de> Visitor<'de> for PeersVisitor {
     where
         E: serde::de::Error,
     {
-        if v.len() % 6 != 0 {
+        if !v.len().is_multiple_of(6) {
             return Err(E::custom("expecting 6 bytes"));
         }
 
@@ -96,7 +171,7 @@ fn urlencode(hash: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * hash.len());
     for &byte in hash {
         encoded.push('%');
-        encoded.push_str(&hex::encode(&[byte]));
+        encoded.push_str(&hex::encode([byte]));
     }
 
     encoded
@@ -0,0 +1,240 @@
+use anyhow::{Context as _, Result};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddrV4;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use crate::ipfilter;
+use crate::mse::{self, Encryption, Rc4};
+use crate::proxy;
+use crate::utp::{UtpSocket, UtpStream};
+
+// Anything a peer connection can run over
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for S {}
+
+// What the peer wire protocol runs over, either the plain stream or the stream
+// with the RC4 cipher negotiated by MSE on top
+pub(crate) struct Transport<S> {
+    stream: S,
+    cipher: Option<Cipher>,
+    // Payload that arrived while setting the connection up, already decrypted
+    buffered: Vec<u8>,
+    // Encrypted bytes the socket hasn't taken yet
+    pending: Vec<u8>,
+}
+
+pub(crate) struct Cipher {
+    pub(crate) incoming: Rc4,
+    pub(crate) outgoing: Rc4,
+}
+
+impl<S: Stream> Transport<S> {
+    pub(crate) fn new(stream: S, cipher: Option<Cipher>, buffered: Vec<u8>) -> Self {
+        Self {
+            stream,
+            cipher,
+            buffered,
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) fn plain(stream: S) -> Self {
+        Self::new(stream, None, Vec::new())
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Stream> AsyncRead for Transport<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let length = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered[..length]);
+            this.buffered.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.cipher {
+            cipher.incoming.apply(&mut buf.filled_mut()[filled..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Stream> AsyncWrite for Transport<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.cipher.is_none() {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+
+        // Once encrypted the bytes have to go out no matter what, so they are
+        // taken in full and written out over however many calls it takes
+        ready!(this.poll_pending(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some(cipher) = &mut this.cipher {
+            cipher.outgoing.apply(&mut this.pending);
+        }
+
+        // Not being able to write it all right now is fine, flushing finishes the job
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+// Connections we make ourselves, which end up on either TCP or uTP
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// How we reach out to peers
+#[derive(Clone)]
+pub(crate) struct Dialer {
+    encryption: Encryption,
+    // Tried before TCP when set
+    utp: Option<Arc<UtpSocket>>,
+}
+
+impl Dialer {
+    pub(crate) fn new(encryption: Encryption, utp: Option<Arc<UtpSocket>>) -> Self {
+        Self { encryption, utp }
+    }
+
+    pub(crate) async fn connect(
+        &self,
+        address: SocketAddrV4,
+        info_hash: &[u8; 20],
+    ) -> Result<Transport<Connection>> {
+        anyhow::ensure!(
+            !ipfilter::is_blocked(address.ip()),
+            "{} is blocked by the IP filter",
+            address.ip()
+        );
+
+        if let Some(utp) = &self.utp {
+            let connect = || async { utp.connect(address).await.map(Connection::Utp) };
+            // Plenty of peers don't do uTP, TCP is still there for them
+            if let Ok(transport) = self.encrypt(connect, info_hash).await {
+                return Ok(transport);
+            }
+        }
+
+        let connect = || async {
+            proxy::connect(&address.ip().to_string(), address.port())
+                .await
+                .map(Connection::Tcp)
+                .context("connecting to peer")
+        };
+        self.encrypt(connect, info_hash).await
+    }
+
+    async fn encrypt<F, Fut>(
+        &self,
+        connect: F,
+        info_hash: &[u8; 20],
+    ) -> Result<Transport<Connection>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<Connection>>,
+    {
+        Ok(match self.encryption {
+            Encryption::Disable => Transport::plain(connect().await?),
+            Encryption::Require => {
+                mse::initiate(connect().await?, info_hash, self.encryption).await?
+            }
+            // Plenty of peers can't do encryption, and don't always say so
+            // nicely, so they get a fresh connection without it
+            Encryption::Prefer => {
+                match mse::initiate(connect().await?, info_hash, self.encryption).await {
+                    Ok(transport) => transport,
+                    Err(_) => Transport::plain(connect().await?),
+                }
+            }
+        })
+    }
+}
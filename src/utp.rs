@@ -0,0 +1,1073 @@
+use anyhow::{Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll};
+use std::time::Duration;
+
+use crate::proxy::Datagrams;
+use crate::rng::Rng;
+
+// uTP from BEP 29, a TCP lookalike over UDP that backs off as soon as it sees
+// queuing delay build up, so it doesn't hog the uplink like TCP would.
+//
+// Every connection runs as its own task, with the socket's receive loop
+// routing packets to it. The stream handed out is one end of an in-memory
+// pipe, the connection task sits at the other end.
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+// Stays under the usual MTU once the IP and UDP headers go on
+const MAX_PAYLOAD: usize = 1400 - HEADER_LENGTH;
+const SELECTIVE_ACK: u8 = 1;
+
+// LEDBAT keeps the queuing delay we cause at around this many microseconds
+const TARGET_DELAY: f64 = 100_000.0;
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const INITIAL_WINDOW: f64 = 2.0 * MAX_PAYLOAD as f64;
+// Base delay is the lowest delay seen over the last couple of these
+const DELAY_HISTORY: Duration = Duration::from_secs(60);
+
+const RECEIVE_WINDOW: usize = 1 << 20;
+// How far past the last in-order packet we keep anything, which also bounds
+// the selective ack mask to 128 bytes
+const REORDER_WINDOW: u16 = 1024;
+// Mask length goes out as a single byte, in multiples of 4
+const MAX_SELECTIVE_ACK: usize = 252;
+// How much the pipes between a connection and its stream hold either way
+const PIPE_SIZE: usize = 64 * 1024;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RETRANSMISSIONS: u32 = 5;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+// Connections a socket keeps track of at once, SYNs past that get reset
+const MAX_CONNECTIONS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data,
+    Fin,
+    State,
+    Reset,
+    Syn,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    window: u32,
+    seq: u16,
+    ack: u16,
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(kind: PacketType, connection_id: u16, seq: u16, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq,
+            ack: 0,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+
+        let kind = match bytes[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        };
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+
+        // Extensions are chained, each one saying what comes after it
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut at = HEADER_LENGTH;
+        while extension != 0 {
+            let header = bytes.get(at..at + 2)?;
+            let data = bytes.get(at + 2..at + 2 + header[1] as usize)?;
+            if extension == SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = header[0];
+            at += 2 + data.len();
+        }
+
+        Some(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq: u16_at(16),
+            ack: u16_at(18),
+            selective_ack,
+            payload: bytes[at..].to_vec(),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(if self.selective_ack.is_some() {
+            SELECTIVE_ACK
+        } else {
+            0
+        });
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        bytes.extend_from_slice(&self.window.to_be_bytes());
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.ack.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            bytes.push(0);
+            let mask = &mask[..mask.len().min(MAX_SELECTIVE_ACK)];
+            bytes.push(mask.len() as u8);
+            bytes.extend_from_slice(mask);
+        }
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+}
+
+// Sequence numbers wrap, so `a` comes before `b` if it is less than half the
+// number space behind
+fn before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+type Routes = Arc<Mutex<HashMap<(SocketAddrV4, u16), mpsc::UnboundedSender<Packet>>>>;
+
+// Handle to a bound UDP port, good for both making and accepting connections
+pub(crate) struct UtpSocket {
+    socket: Arc<Datagrams>,
+    routes: Routes,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<(UtpStream, SocketAddrV4)>>,
+    epoch: Instant,
+}
+
+impl UtpSocket {
+    pub(crate) async fn bind(port: u16) -> Result<Arc<Self>> {
+        Ok(Self::start(Datagrams::bind(port).await?))
+    }
+
+    // Through the SOCKS5 proxy, for when nothing is allowed out directly
+    pub(crate) async fn associate() -> Result<Arc<Self>> {
+        Ok(Self::start(Datagrams::associate().await?))
+    }
+
+    fn start(socket: Datagrams) -> Arc<Self> {
+        let socket = Arc::new(socket);
+        let routes = Routes::default();
+        let (tx, incoming) = mpsc::unbounded_channel();
+        let epoch = Instant::now();
+
+        tokio::spawn(receive(socket.clone(), routes.clone(), tx, epoch));
+
+        Arc::new(Self {
+            socket,
+            routes,
+            incoming: tokio::sync::Mutex::new(incoming),
+            epoch,
+        })
+    }
+
+    pub(crate) async fn connect(&self, address: SocketAddrV4) -> Result<UtpStream> {
+        let mut rng = Rng::new();
+        let (tx, packets) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut routes = self.routes.lock().expect("routes lock poisoned");
+            let recv_id = loop {
+                let id = rng.below(1 << 16) as u16;
+                if !routes.contains_key(&(address, id)) {
+                    break id;
+                }
+            };
+            routes.insert((address, recv_id), tx);
+            recv_id
+        };
+
+        let (stream, pipe) = tokio::io::duplex(PIPE_SIZE);
+        let (connected_tx, connected) = oneshot::channel();
+        let connection = Connection::new(
+            self.socket.clone(),
+            address,
+            recv_id.wrapping_add(1),
+            recv_id,
+            State::SynSent(connected_tx),
+            self.epoch,
+        );
+        tokio::spawn(connection.run(packets, pipe, self.routes.clone()));
+
+        time::timeout(CONNECT_TIMEOUT, connected)
+            .await
+            .context("timed out connecting over uTP")?
+            .context("uTP connection failed")?;
+
+        Ok(UtpStream(stream))
+    }
+
+    pub(crate) async fn accept(&self) -> Result<(UtpStream, SocketAddrV4)> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("uTP socket went away")
+    }
+}
+
+async fn receive(
+    socket: Arc<Datagrams>,
+    routes: Routes,
+    incoming: mpsc::UnboundedSender<(UtpStream, SocketAddrV4)>,
+    epoch: Instant,
+) {
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        // Errors on an unconnected UDP socket are about some earlier datagram,
+        // nothing to stop for
+        let Ok((length, from)) = socket.recv_from(&mut buffer).await else {
+            continue;
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Some(packet) = Packet::parse(&buffer[..length]) else {
+            continue;
+        };
+
+        // Everything but the SYN is addressed to the id we receive on, which
+        // is one more than the id the SYN came with
+        let recv_id = match packet.kind {
+            PacketType::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let (route, full) = {
+            let routes = routes.lock().expect("routes lock poisoned");
+            (
+                routes.get(&(from, recv_id)).cloned(),
+                routes.len() >= MAX_CONNECTIONS,
+            )
+        };
+
+        match route {
+            Some(route) => {
+                let _ = route.send(packet);
+            }
+            None if packet.kind == PacketType::Syn && !full => {
+                let (tx, packets) = mpsc::unbounded_channel();
+                routes
+                    .lock()
+                    .expect("routes lock poisoned")
+                    .insert((from, recv_id), tx);
+
+                let (stream, pipe) = tokio::io::duplex(PIPE_SIZE);
+                let mut connection = Connection::new(
+                    socket.clone(),
+                    from,
+                    packet.connection_id,
+                    recv_id,
+                    State::Connected,
+                    epoch,
+                );
+                connection.seq = Rng::new().below(1 << 16) as u16;
+                connection.ack = packet.seq;
+                connection.reply_delay = connection.now().wrapping_sub(packet.timestamp);
+                connection.need_ack = true;
+                tokio::spawn(connection.run(packets, pipe, routes.clone()));
+
+                // Nobody accepting means the stream gets dropped, which
+                // closes the connection again
+                let _ = incoming.send((UtpStream(stream), from));
+            }
+            // Let them know we have no idea what they are on about, or have no
+            // room for them
+            None if packet.kind != PacketType::Reset => {
+                let mut reset = Packet::new(PacketType::Reset, packet.connection_id, 0, Vec::new());
+                reset.ack = packet.seq;
+                let _ = socket.send_to(&reset.encode(), from).await;
+            }
+            None => {}
+        }
+    }
+}
+
+enum State {
+    SynSent(oneshot::Sender<()>),
+    Connected,
+}
+
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+// Lowest delay seen in the current and previous stretch of time, since the
+// clocks at both ends are unrelated this is the closest thing to no queuing
+struct DelayHistory {
+    current: u32,
+    previous: u32,
+    started: Instant,
+}
+
+impl DelayHistory {
+    fn add(&mut self, sample: u32) -> u32 {
+        if self.started.elapsed() > DELAY_HISTORY {
+            self.previous = self.current;
+            self.current = u32::MAX;
+            self.started = Instant::now();
+        }
+        self.current = self.current.min(sample);
+
+        sample.wrapping_sub(self.current.min(self.previous))
+    }
+}
+
+struct Connection {
+    socket: Arc<Datagrams>,
+    remote: SocketAddrV4,
+    send_id: u16,
+    recv_id: u16,
+    state: State,
+    epoch: Instant,
+    // Next sequence number we send and the last one we received in order
+    seq: u16,
+    ack: u16,
+    need_ack: bool,
+
+    // Sent but not acknowledged yet, oldest first
+    unacked: VecDeque<Sent>,
+    in_flight: usize,
+    window: f64,
+    remote_window: usize,
+    rtt: Option<(f64, f64)>,
+    timeout: Duration,
+    timeouts: u32,
+    duplicate_acks: u32,
+    last_ack: u16,
+    last_loss: Instant,
+    delays: DelayHistory,
+    // How long their last packet took to get here by our clock, which they
+    // need for their own delay measurements
+    reply_delay: u32,
+    last_received: Instant,
+
+    // In order data waiting for the stream to read it, and data that arrived
+    // ahead of something still missing
+    received: Vec<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    fin_sent: bool,
+    fin_received: Option<u16>,
+    eof_delivered: bool,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<Datagrams>,
+        remote: SocketAddrV4,
+        send_id: u16,
+        recv_id: u16,
+        state: State,
+        epoch: Instant,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            socket,
+            remote,
+            send_id,
+            recv_id,
+            state,
+            epoch,
+            seq: 1,
+            ack: 0,
+            need_ack: false,
+            unacked: VecDeque::new(),
+            in_flight: 0,
+            window: INITIAL_WINDOW,
+            remote_window: RECEIVE_WINDOW,
+            rtt: None,
+            timeout: INITIAL_TIMEOUT,
+            timeouts: 0,
+            duplicate_acks: 0,
+            last_ack: 0,
+            last_loss: now,
+            delays: DelayHistory {
+                current: u32::MAX,
+                previous: u32::MAX,
+                started: now,
+            },
+            reply_delay: 0,
+            last_received: now,
+            received: Vec::new(),
+            out_of_order: HashMap::new(),
+            fin_sent: false,
+            fin_received: None,
+            eof_delivered: false,
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut packets: mpsc::UnboundedReceiver<Packet>,
+        pipe: DuplexStream,
+        routes: Routes,
+    ) {
+        // What's left to say is said with a FIN or a reset, no point
+        // reporting anything
+        let _ = self.exchange(&mut packets, pipe).await;
+
+        routes
+            .lock()
+            .expect("routes lock poisoned")
+            .remove(&(self.remote, self.recv_id));
+    }
+
+    async fn exchange(
+        &mut self,
+        packets: &mut mpsc::UnboundedReceiver<Packet>,
+        pipe: DuplexStream,
+    ) -> Result<()> {
+        let (mut reader, mut writer) = tokio::io::split(pipe);
+        let mut buffer = vec![0; MAX_PAYLOAD];
+
+        if matches!(self.state, State::SynSent(_)) {
+            self.send(PacketType::Syn, Vec::new()).await;
+        }
+
+        loop {
+            if self.need_ack {
+                self.send_state().await;
+            }
+
+            if self.fin_sent && self.unacked.is_empty() && self.eof_delivered {
+                return Ok(());
+            }
+
+            // Always let one packet through, however small the window gets
+            let window = (self.window as usize).min(self.remote_window);
+            let can_send = matches!(self.state, State::Connected)
+                && !self.fin_sent
+                && (self.in_flight == 0 || self.in_flight + MAX_PAYLOAD <= window);
+            let can_deliver = !self.received.is_empty();
+
+            let event = tokio::select! {
+                packet = packets.recv() => Event::Packet(packet),
+                read = reader.read(&mut buffer), if can_send => Event::Read(read),
+                written = writer.write(&self.received), if can_deliver => Event::Written(written),
+                _ = time::sleep_until(self.deadline()) => Event::Timeout,
+            };
+
+            match event {
+                Event::Packet(None) => return Ok(()),
+                Event::Packet(Some(packet)) => {
+                    self.handle(packet).await?;
+                    while let Ok(packet) = packets.try_recv() {
+                        self.handle(packet).await?;
+                    }
+                }
+                // The stream is done writing, so we are too
+                Event::Read(Ok(0) | Err(_)) => {
+                    self.fin_sent = true;
+                    self.send(PacketType::Fin, Vec::new()).await;
+                }
+                Event::Read(Ok(length)) => {
+                    self.send(PacketType::Data, buffer[..length].to_vec()).await;
+                }
+                Event::Written(Ok(length)) => {
+                    self.received.drain(..length);
+                }
+                // Nobody is reading anymore, throw it all away
+                Event::Written(Err(_)) => {
+                    self.received.clear();
+                    self.eof_delivered = true;
+                }
+                Event::Timeout => self.check_timeouts().await?,
+            }
+
+            if !self.eof_delivered
+                && self.received.is_empty()
+                && self.fin_received.is_some_and(|fin| fin == self.ack)
+            {
+                let _ = writer.shutdown().await;
+                self.eof_delivered = true;
+            }
+        }
+    }
+
+    fn now(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    fn deadline(&self) -> Instant {
+        let idle = self.last_received + IDLE_TIMEOUT;
+        match self.unacked.front() {
+            Some(oldest) => idle.min(oldest.sent_at + self.timeout),
+            None => idle,
+        }
+    }
+
+    async fn check_timeouts(&mut self) -> Result<()> {
+        let now = Instant::now();
+        anyhow::ensure!(
+            now < self.last_received + IDLE_TIMEOUT,
+            "uTP connection went quiet"
+        );
+
+        let Some(oldest) = self.unacked.front() else {
+            return Ok(());
+        };
+        if now < oldest.sent_at + self.timeout {
+            return Ok(());
+        }
+
+        self.timeouts += 1;
+        anyhow::ensure!(
+            self.timeouts <= MAX_RETRANSMISSIONS,
+            "uTP connection timed out"
+        );
+
+        // Back to square one, whatever was going on clearly wasn't working
+        self.window = MIN_WINDOW;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.retransmit(0).await;
+
+        Ok(())
+    }
+
+    async fn handle(&mut self, packet: Packet) -> Result<()> {
+        self.last_received = Instant::now();
+        self.reply_delay = self.now().wrapping_sub(packet.timestamp);
+        self.remote_window = packet.window as usize;
+
+        match packet.kind {
+            PacketType::Reset => anyhow::bail!("uTP connection reset"),
+            // Our answer to their SYN got lost
+            PacketType::Syn => {
+                self.need_ack = true;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        if let State::SynSent(_) = self.state {
+            if packet.kind != PacketType::State {
+                return Ok(());
+            }
+
+            // Their first data packet will carry the number they acked with
+            self.ack = packet.seq.wrapping_sub(1);
+            if let State::SynSent(connected) = std::mem::replace(&mut self.state, State::Connected)
+            {
+                let _ = connected.send(());
+            }
+        }
+
+        self.process_ack(&packet).await;
+
+        match packet.kind {
+            PacketType::Data => self.receive(packet.seq, packet.payload),
+            PacketType::Fin => {
+                self.fin_received = Some(packet.seq);
+                self.receive(packet.seq, Vec::new());
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn receive(&mut self, seq: u16, payload: Vec<u8>) {
+        self.need_ack = true;
+        if !before(self.ack, seq) {
+            return;
+        }
+        // Too far ahead to be anything we'd have room for, or to describe in
+        // a selective ack
+        if seq.wrapping_sub(self.ack) > REORDER_WINDOW {
+            return;
+        }
+
+        let buffered = self.received.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|payload| payload.len())
+                .sum::<usize>();
+        if buffered + payload.len() > RECEIVE_WINDOW {
+            return;
+        }
+        self.out_of_order.insert(seq, payload);
+
+        loop {
+            let next = self.ack.wrapping_add(1);
+            let Some(payload) = self.out_of_order.remove(&next) else {
+                break;
+            };
+            self.received.extend_from_slice(&payload);
+            self.ack = next;
+        }
+    }
+
+    async fn process_ack(&mut self, packet: &Packet) {
+        // Acking something we haven't even sent is nonsense, and taking it
+        // at its word would throw away packets still in flight
+        if !before(packet.ack, self.seq) {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut acked = 0;
+        let mut rtt_sample = None;
+
+        let is_sacked = |seq: u16| {
+            let Some(mask) = &packet.selective_ack else {
+                return false;
+            };
+            // The mask starts at the packet after the one that is missing
+            let offset = seq.wrapping_sub(packet.ack).wrapping_sub(2) as usize;
+            offset < mask.len() * 8 && mask[offset / 8] >> (offset % 8) & 1 == 1
+        };
+
+        // Anything with three selectively acked packets after it is lost
+        let mut sacked_after = 0;
+        let mut lost = Vec::new();
+        for sent in self.unacked.iter().rev() {
+            let seq = sent.packet.seq;
+            if !before(packet.ack, seq) {
+                continue;
+            }
+            if is_sacked(seq) {
+                sacked_after += 1;
+            } else if sacked_after >= 3 {
+                lost.push(seq);
+            }
+        }
+
+        let mut remaining = VecDeque::with_capacity(self.unacked.len());
+        for sent in self.unacked.drain(..) {
+            let seq = sent.packet.seq;
+            if before(packet.ack, seq) && !is_sacked(seq) {
+                remaining.push_back(sent);
+                continue;
+            }
+
+            acked += sent.packet.payload.len();
+            self.in_flight -= sent.packet.payload.len();
+            // Retransmitted packets can't tell which copy got acked
+            if sent.transmissions == 1 {
+                rtt_sample = Some(now.duration_since(sent.sent_at));
+            }
+        }
+        self.unacked = remaining;
+
+        if let Some(sample) = rtt_sample {
+            self.update_rtt(sample);
+        }
+
+        if acked > 0 {
+            self.timeouts = 0;
+            self.duplicate_acks = 0;
+            self.update_window(acked, packet.timestamp_difference);
+        } else if packet.kind == PacketType::State
+            && packet.ack == self.last_ack
+            && !self.unacked.is_empty()
+        {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == 3 {
+                self.lost();
+                self.retransmit(0).await;
+            }
+        }
+        self.last_ack = packet.ack;
+
+        if lost.is_empty() {
+            return;
+        }
+        self.lost();
+        // Whatever went out again within the last round trip gets a chance first
+        let rtt = self.smoothed_rtt();
+        for index in 0..self.unacked.len() {
+            let sent = &self.unacked[index];
+            if lost.contains(&sent.packet.seq) && sent.sent_at + rtt < now {
+                self.retransmit(index).await;
+            }
+        }
+    }
+
+    fn smoothed_rtt(&self) -> Duration {
+        self.rtt
+            .map_or(self.timeout, |(rtt, _)| Duration::from_secs_f64(rtt))
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let sample = sample.as_secs_f64();
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2.0),
+            Some((rtt, variance)) => (
+                rtt + (sample - rtt) / 8.0,
+                variance + ((rtt - sample).abs() - variance) / 4.0,
+            ),
+        };
+        self.rtt = Some((rtt, variance));
+        self.timeout =
+            Duration::from_secs_f64(rtt + 4.0 * variance).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    // LEDBAT: grow the window while the delay we add is under target, shrink it
+    // when over, in proportion to how much of the window just got acked
+    fn update_window(&mut self, acked: usize, delay: u32) {
+        if delay == 0 {
+            return;
+        }
+
+        let queuing = self.delays.add(delay) as f64;
+        let off_target = ((TARGET_DELAY - queuing) / TARGET_DELAY).clamp(-1.0, 1.0);
+        let acked = acked as f64;
+        let window_factor = acked.min(self.window) / acked.max(self.window);
+        self.window =
+            (self.window + MAX_WINDOW_INCREASE * off_target * window_factor).max(MIN_WINDOW);
+    }
+
+    // Loss halves the window, though only once per round trip
+    fn lost(&mut self) {
+        if self.last_loss.elapsed() > self.smoothed_rtt() {
+            self.window = (self.window / 2.0).max(MIN_WINDOW);
+            self.last_loss = Instant::now();
+        }
+    }
+
+    async fn send(&mut self, kind: PacketType, payload: Vec<u8>) {
+        // The SYN is the odd one out, it tells them the id we receive on
+        let connection_id = match kind {
+            PacketType::Syn => self.recv_id,
+            _ => self.send_id,
+        };
+        let packet = Packet::new(kind, connection_id, self.seq, payload);
+        self.seq = self.seq.wrapping_add(1);
+        self.in_flight += packet.payload.len();
+        self.unacked.push_back(Sent {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 0,
+        });
+        self.retransmit(self.unacked.len() - 1).await;
+    }
+
+    async fn retransmit(&mut self, index: usize) {
+        let Some(sent) = self.unacked.get_mut(index) else {
+            return;
+        };
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        let mut packet = sent.packet.clone();
+        self.transmit(&mut packet).await;
+    }
+
+    // Acks without any data, the sequence number is the next one we'll use
+    async fn send_state(&mut self) {
+        let mut packet = Packet::new(PacketType::State, self.send_id, self.seq, Vec::new());
+        if !self.out_of_order.is_empty() {
+            packet.selective_ack = Some(self.selective_ack());
+        }
+        self.transmit(&mut packet).await;
+        self.need_ack = false;
+    }
+
+    fn selective_ack(&self) -> Vec<u8> {
+        let furthest = self
+            .out_of_order
+            .keys()
+            .map(|seq| seq.wrapping_sub(self.ack).wrapping_sub(2) as usize)
+            .max()
+            .unwrap_or(0);
+        // Masks come in multiples of 32 bits
+        let mut mask = vec![0u8; (furthest / 32 + 1) * 4];
+        for seq in self.out_of_order.keys() {
+            let offset = seq.wrapping_sub(self.ack).wrapping_sub(2) as usize;
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+        mask
+    }
+
+    async fn transmit(&mut self, packet: &mut Packet) {
+        let buffered = self.received.len()
+            + self
+                .out_of_order
+                .values()
+                .map(|payload| payload.len())
+                .sum::<usize>();
+
+        packet.timestamp = self.now();
+        packet.timestamp_difference = self.reply_delay;
+        packet.window = RECEIVE_WINDOW.saturating_sub(buffered) as u32;
+        packet.ack = self.ack;
+
+        // Lost datagrams are what the retransmissions are for
+        let _ = self.socket.send_to(&packet.encode(), self.remote).await;
+    }
+}
+
+enum Event {
+    Packet(Option<Packet>),
+    Read(io::Result<usize>),
+    Written(io::Result<usize>),
+    Timeout,
+}
+
+// A connected uTP stream, read and written like a TcpStream
+pub(crate) struct UtpStream(DuplexStream);
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn packet() -> Packet {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 0xfffe, b"payload".to_vec());
+        packet.timestamp = 0xdead_beef;
+        packet.timestamp_difference = 1000;
+        packet.window = RECEIVE_WINDOW as u32;
+        packet.ack = 42;
+        packet
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = packet();
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), HEADER_LENGTH + 7);
+        assert_eq!(bytes[0], 0x01);
+        assert_eq!(Packet::parse(&bytes), Some(packet.clone()));
+
+        let mut sacked = packet;
+        sacked.kind = PacketType::State;
+        sacked.payload.clear();
+        sacked.selective_ack = Some(vec![0x01, 0, 0, 0x80]);
+        assert_eq!(Packet::parse(&sacked.encode()), Some(sacked));
+    }
+
+    #[test]
+    fn packet_malformed() {
+        let bytes = packet().encode();
+        assert_eq!(Packet::parse(&bytes[..HEADER_LENGTH - 1]), None);
+        assert_eq!(Packet::parse(&[]), None);
+
+        let mut version = bytes.clone();
+        version[0] = 0x02;
+        assert_eq!(Packet::parse(&version), None);
+
+        let mut kind = bytes.clone();
+        kind[0] = 5 << 4 | VERSION;
+        assert_eq!(Packet::parse(&kind), None);
+
+        // An extension that runs past the end of the packet
+        let mut sacked = packet();
+        sacked.payload.clear();
+        sacked.selective_ack = Some(vec![0xff; 8]);
+        let bytes = sacked.encode();
+        assert_eq!(Packet::parse(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Packet::parse(&bytes[..HEADER_LENGTH + 1]), None);
+    }
+
+    #[test]
+    fn sequence_wraparound() {
+        assert!(before(1, 2));
+        assert!(!before(2, 1));
+        assert!(!before(5, 5));
+        assert!(before(u16::MAX, 0));
+        assert!(before(65000, 100));
+        assert!(!before(100, 65000));
+        assert!(before(0, 32767));
+        assert!(!before(32767, 0));
+    }
+
+    #[test]
+    fn selective_ack_capped() {
+        for (length, encoded) in [(4, 4), (MAX_SELECTIVE_ACK, MAX_SELECTIVE_ACK), (300, 252)] {
+            let mut sacked = packet();
+            sacked.selective_ack = Some(vec![0xaa; length]);
+            let parsed = Packet::parse(&sacked.encode()).unwrap();
+            assert_eq!(parsed.selective_ack.unwrap().len(), encoded);
+            assert_eq!(parsed.payload, b"payload");
+        }
+    }
+
+    async fn connection() -> Connection {
+        let socket = Arc::new(Datagrams::bind(0).await.unwrap());
+        // Nobody listens there, what gets sent goes nowhere
+        let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9);
+        Connection::new(socket, remote, 1, 0, State::Connected, Instant::now())
+    }
+
+    #[tokio::test]
+    async fn receive_window() {
+        let mut connection = connection().await;
+        connection.ack = u16::MAX - 10;
+
+        // The furthest packet we hold on to, right at the end of the mask
+        let furthest = connection.ack.wrapping_add(REORDER_WINDOW);
+        connection.receive(furthest, vec![1]);
+        connection.receive(furthest.wrapping_add(1), vec![2]);
+        connection.receive(connection.ack.wrapping_add(2), vec![3]);
+        assert_eq!(connection.out_of_order.len(), 2);
+
+        let mask = connection.selective_ack();
+        assert!(mask.len() <= MAX_SELECTIVE_ACK);
+        assert_eq!(mask.len(), 128);
+        assert_eq!(mask[0], 0x01);
+        let offset = REORDER_WINDOW as usize - 2;
+        assert_eq!(mask[offset / 8] >> (offset % 8) & 1, 1);
+
+        // Filling the gap delivers everything up to the next one missing
+        connection.receive(connection.ack.wrapping_add(1), vec![4]);
+        assert_eq!(connection.received, [4, 3]);
+        assert_eq!(connection.out_of_order.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn ack_beyond_sent() {
+        let mut connection = connection().await;
+        connection.send(PacketType::Data, vec![0; 10]).await;
+        connection.send(PacketType::Data, vec![0; 10]).await;
+        assert_eq!(connection.seq, 3);
+
+        let mut ack = Packet::new(PacketType::State, 0, 100, Vec::new());
+        ack.ack = 1000;
+        connection.process_ack(&ack).await;
+        assert_eq!(connection.unacked.len(), 2);
+        assert_eq!(connection.in_flight, 20);
+
+        ack.ack = 1;
+        connection.process_ack(&ack).await;
+        assert_eq!(connection.unacked.len(), 1);
+        ack.ack = 2;
+        connection.process_ack(&ack).await;
+        assert!(connection.unacked.is_empty());
+        assert_eq!(connection.in_flight, 0);
+    }
+
+    fn free_port() -> u16 {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    async fn transfer(mut stream: UtpStream, data: Vec<u8>) -> Vec<u8> {
+        let (mut reader, mut writer) = tokio::io::split(&mut stream);
+        let send = async {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut received = Vec::new();
+        let receive = reader.read_to_end(&mut received);
+        let (_, read) = tokio::join!(send, receive);
+        read.unwrap();
+        received
+    }
+
+    #[tokio::test]
+    async fn loopback() {
+        let port = free_port();
+        let server = UtpSocket::bind(port).await.unwrap();
+        let client = UtpSocket::bind(0).await.unwrap();
+
+        let upload: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let download: Vec<u8> = (0..200_000).map(|i| (i % 241) as u8).collect();
+        let (connected, accepted) = tokio::join!(
+            client.connect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)),
+            server.accept()
+        );
+        let (accepted, from) = accepted.unwrap();
+        assert_eq!(from.ip(), &Ipv4Addr::LOCALHOST);
+
+        let (downloaded, uploaded) = time::timeout(Duration::from_secs(30), async {
+            tokio::join!(
+                transfer(connected.unwrap(), upload.clone()),
+                transfer(accepted, download.clone())
+            )
+        })
+        .await
+        .unwrap();
+        assert!(downloaded == download);
+        assert!(uploaded == upload);
+    }
+
+    #[tokio::test]
+    async fn connections_capped() {
+        let port = free_port();
+        let server = UtpSocket::bind(port).await.unwrap();
+        let client = UtpSocket::bind(0).await.unwrap();
+        {
+            let mut routes = server.routes.lock().unwrap();
+            for id in 0..MAX_CONNECTIONS as u16 {
+                let somewhere = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), id);
+                routes.insert((somewhere, id), mpsc::unbounded_channel().0);
+            }
+        }
+
+        let connected = client
+            .connect(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
+            .await;
+        assert!(connected.is_err());
+    }
+}